@@ -0,0 +1,81 @@
+use std::cmp::max;
+use std::os::unix::io::AsRawFd;
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use crate::cli::Cli;
+
+//aligner processes started for --reads, one per input FASTA
+pub struct Aligners {
+    children: Vec<Child>,
+    //stdout pipes of the children, kept open for as long as their paths are read
+    pipes: Vec<ChildStdout>,
+    labels: Vec<String>,
+    //paths the SAM output of each aligner can be opened from
+    pub paths: Vec<String>,
+}
+
+//split the --aligner template into a program and its arguments and fill in the placeholders
+//placeholders are replaced after splitting, so paths containing spaces stay one argument
+fn aligner_command(template: &str, reference: &str, reads: &str, threads: usize) -> Result<Command, String> {
+    let mut words = template.split_whitespace().map(|w| w
+        .replace("{ref}", reference)
+        .replace("{reads}", reads)
+        .replace("{threads}", &threads.to_string()));
+    let program = words.next().ok_or("--aligner is empty")?;
+    let mut cmd = Command::new(program);
+    cmd.args(words);
+    Ok(cmd)
+}
+
+//start the aligner for every input FASTA, each writing SAM into a pipe read by diplinator
+pub fn spawn_aligners(args: &Cli, reads: &str) -> Result<Aligners, Box<dyn std::error::Error>> {
+    let labels = args.input_labels()?;
+    //share the thread budget between the aligners, the assignment itself needs little
+    let threads = max(1, args.threads / args.inputs.len());
+    let mut aligners = Aligners { children: Vec::new(), pipes: Vec::new(), labels: labels.clone(), paths: Vec::new() };
+
+    for (reference, label) in args.inputs.iter().zip(labels.iter()) {
+        let mut cmd = aligner_command(&args.aligner, reference, reads, threads)?;
+        eprintln!("Aligning reads to {}: {:?}", label, cmd);
+        //stderr is passed through so aligner progress and errors stay visible
+        let mut child = cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::inherit()).spawn()
+            .map_err(|e| format!("Failed to start aligner for {}: {}", label, e))?;
+        let pipe = child.stdout.take().unwrap();
+        //htslib opens inputs by path, the pipe is reachable through the process' fd directory
+        aligners.paths.push(format!("/dev/fd/{}", pipe.as_raw_fd()));
+        aligners.pipes.push(pipe);
+        aligners.children.push(child);
+    }
+    Ok(aligners)
+}
+
+impl Aligners {
+    //wait for every aligner to exit, given the result of reading their output
+    //a failed aligner is reported in place of the read error it usually causes
+    pub fn finish(mut self, result: Result<(), Box<dyn std::error::Error>>) -> Result<(), Box<dyn std::error::Error>> {
+        //closing the pipes lets aligners that are still writing exit once reading has stopped
+        self.pipes.clear();
+        let mut children = std::mem::take(&mut self.children);
+        for (child, label) in children.iter_mut().zip(self.labels.iter()) {
+            if result.is_err() {
+                let _ = child.kill();
+            }
+            let status = child.wait()
+                .map_err(|e| format!("Failed to wait for aligner of {}: {}", label, e))?;
+            if !status.success() && (result.is_ok() || status.code().is_some()) {
+                return Err(format!("Aligner for {} failed ({})", label, status).into());
+            }
+        }
+        result
+    }
+}
+
+impl Drop for Aligners {
+    //do not leave aligners running if diplinator stops early
+    fn drop(&mut self) {
+        for child in self.children.iter_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use std::io::Write;
+
+use rust_htslib::bam::{self, record::Aux, record::Cigar, Record};
+use rust_htslib::errors::Error as BamError;
+
+use crate::cli::Cli;
+use crate::paf;
+use crate::sam::{self, SamWriter};
+
+//reads binned by haplotype: every assigned read is written once, from the primary record(s) of the winning cluster,
+//with SEQ/QUAL back in the orientation the read was sequenced in (MM/ML refer to that orientation already)
+pub(crate) enum ReadBin {
+    //FASTQ, BGZF-compressed with --bgzf or a .gz path; tags follow the read name, as with samtools fastq -T
+    Fastq { writer: Box<dyn Write>, tags: Vec<String> },
+    //unaligned BAM holding the @RG/@PG lines of the input(s)
+    Ubam { writer: SamWriter, tags: Vec<String> },
+}
+
+impl ReadBin {
+    pub(crate) fn create_fastq(path: &str, compress: bool, args: &Cli) -> Result<Self, Box<dyn std::error::Error>> {
+        //BGZF is the same writer as for PAF
+        let writer = paf::create_paf(path, compress, args.level)?;
+        Ok(ReadBin::Fastq { writer, tags: args.bin_tags.clone() })
+    }
+
+    pub(crate) fn create_ubam(path: &str, views: &[&bam::HeaderView], args: &Cli) -> Result<Self, String> {
+        let writer = SamWriter::create_unaligned(path, &unaligned_header(views), args)?;
+        Ok(ReadBin::Ubam { writer, tags: args.bin_tags.clone() })
+    }
+
+    pub(crate) fn set_threads(&mut self, n_threads: usize) -> Result<(), BamError> {
+        match self {
+            ReadBin::Ubam { writer, .. } => writer.set_threads(n_threads),
+            ReadBin::Fastq { .. } => Ok(()),
+        }
+    }
+
+    //write the read of a cluster once, one record per mate for paired reads
+    //hapq and label are added as hq/hl tags like in alignment output
+    //false if the read was not written, as no record of one of its mates holds its full sequence
+    pub(crate) fn write_read(&mut self, cluster: &[Record], hapq: Option<u8>, label: Option<&str>) -> Result<bool, Box<dyn std::error::Error>> {
+        let mut mates = Vec::with_capacity(2);
+        for rec in cluster.iter().filter(|r| !r.is_secondary() && !r.is_supplementary()) {
+            match full_seq(cluster, rec) {
+                Some(seq) => mates.push((rec, seq)),
+                None => return Ok(false),
+            }
+        }
+        for (rec, (seq, qual)) in mates {
+            match self {
+                ReadBin::Fastq { writer, tags } => {
+                    let mut name = String::from_utf8_lossy(rec.qname()).into_owned();
+                    match sam::mate(rec) {
+                        1 => name.push_str("/1"),
+                        2 => name.push_str("/2"),
+                        _ => {}
+                    }
+                    for tag in tags.iter() {
+                        if let Ok(aux) = rec.aux(tag.as_bytes()) {
+                            name.push('\t');
+                            name.push_str(&aux_text(tag, &aux));
+                        }
+                    }
+                    if let Some(hq) = hapq { name.push_str(&format!("\thq:i:{}", hq)); }
+                    if let Some(label) = label { name.push_str(&format!("\thl:Z:{}", label)); }
+                    let qual: Vec<u8> = qual.iter().map(|q| q + 33).collect();
+                    writer.write_all(b"@")?;
+                    writer.write_all(name.as_bytes())?;
+                    writer.write_all(b"\n")?;
+                    writer.write_all(&seq)?;
+                    writer.write_all(b"\n+\n")?;
+                    writer.write_all(&qual)?;
+                    writer.write_all(b"\n")?;
+                }
+                ReadBin::Ubam { writer, tags } => {
+                    let mut out = Record::new();
+                    out.set(rec.qname(), None, &seq, &qual);
+                    out.set_tid(-1);
+                    out.set_pos(-1);
+                    out.set_mtid(-1);
+                    out.set_mpos(-1);
+                    //unmapped, keeping only which mate of a pair the read is
+                    let paired = if rec.is_paired() { rec.flags() & (0x1 | 0x40 | 0x80) | 0x8 } else { 0 };
+                    out.set_flags(0x4 | paired);
+                    for tag in tags.iter() {
+                        if let Ok(aux) = rec.aux(tag.as_bytes()) {
+                            out.push_aux(tag.as_bytes(), aux)?;
+                        }
+                    }
+                    if let Some(hq) = hapq { out.push_aux(b"hq", Aux::U8(hq))?; }
+                    if let Some(label) = label { out.push_aux(b"hl", Aux::String(label))?; }
+                    writer.write(&out)?;
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    pub(crate) fn finish(self) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            ReadBin::Fastq { mut writer, .. } => Ok(writer.flush()?),
+            ReadBin::Ubam { writer, .. } => writer.finish(),
+        }
+    }
+}
+
+//full SEQ and QUAL of the mate of a primary record: from the primary itself,
+//else from another record of the same mate that is neither hard clipped nor without SEQ
+fn full_seq(cluster: &[Record], primary: &Record) -> Option<(Vec<u8>, Vec<u8>)> {
+    let full = |rec: &Record| rec.seq_len() > 0 && !rec.cigar().iter().any(|c| matches!(c, Cigar::HardClip(_)));
+    if full(primary) {
+        return Some(original_seq(primary));
+    }
+    cluster.iter().find(|r| sam::mate(r) == sam::mate(primary) && full(r)).map(original_seq)
+}
+
+//SEQ and QUAL (without offset) of a record with the full read sequence, in sequencing orientation,
+//missing qualities become 1 like in samtools fastq
+fn original_seq(rec: &Record) -> (Vec<u8>, Vec<u8>) {
+    let mut seq = rec.seq().as_bytes();
+    let mut qual = rec.qual().to_vec();
+    if qual.first() == Some(&255) {
+        qual = vec![1; seq.len()];
+    }
+    if rec.is_reverse() {
+        seq = sam::revcomp(&seq);
+        qual.reverse();
+    }
+    (seq, qual)
+}
+
+//an aux field as SAM text, e.g. MM:Z:C+m,0,1; or ML:B:C,200,12
+fn aux_text(tag: &str, aux: &Aux) -> String {
+    fn array<T: ToString>(values: impl Iterator<Item = T>) -> String {
+        values.map(|v| v.to_string()).collect::<Vec<String>>().join(",")
+    }
+    let (ty, value) = match aux {
+        Aux::Char(c) => ("A", (*c as char).to_string()),
+        Aux::I8(v) => ("i", v.to_string()),
+        Aux::U8(v) => ("i", v.to_string()),
+        Aux::I16(v) => ("i", v.to_string()),
+        Aux::U16(v) => ("i", v.to_string()),
+        Aux::I32(v) => ("i", v.to_string()),
+        Aux::U32(v) => ("i", v.to_string()),
+        Aux::Float(v) => ("f", v.to_string()),
+        Aux::Double(v) => ("f", v.to_string()),
+        Aux::String(v) => ("Z", v.to_string()),
+        Aux::HexByteArray(v) => ("H", v.to_string()),
+        Aux::ArrayI8(a) => ("B", format!("c,{}", array(a.iter()))),
+        Aux::ArrayU8(a) => ("B", format!("C,{}", array(a.iter()))),
+        Aux::ArrayI16(a) => ("B", format!("s,{}", array(a.iter()))),
+        Aux::ArrayU16(a) => ("B", format!("S,{}", array(a.iter()))),
+        Aux::ArrayI32(a) => ("B", format!("i,{}", array(a.iter()))),
+        Aux::ArrayU32(a) => ("B", format!("I,{}", array(a.iter()))),
+        Aux::ArrayFloat(a) => ("B", format!("f,{}", array(a.iter()))),
+    };
+    format!("{}:{}:{}", tag, ty, value)
+}
+
+//header of unaligned BAM output: no @SQ lines, the @RG/@PG lines of every input once per ID and their @CO lines
+fn unaligned_header(views: &[&bam::HeaderView]) -> bam::Header {
+    let mut text = String::from("@HD\tVN:1.6\tSO:unsorted\tGO:query\n");
+    let mut seen: Vec<String> = Vec::new();
+    for view in views {
+        for line in String::from_utf8_lossy(view.as_bytes()).lines() {
+            let key = match &line[..line.len().min(3)] {
+                "@RG" | "@PG" => line.split('\t').find(|f| f.starts_with("ID:")).map(|id| format!("{}{}", &line[..3], id)),
+                "@CO" => Some(line.to_string()),
+                _ => None,
+            };
+            if let Some(key) = key {
+                if !seen.contains(&key) {
+                    seen.push(key);
+                    text.push_str(line);
+                    text.push('\n');
+                }
+            }
+        }
+    }
+    bam::Header::from_template(&bam::HeaderView::from_bytes(text.as_bytes()))
+}
@@ -0,0 +1,469 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use serde::Deserialize;
+
+use crate::cli::Cli;
+use crate::open_text;
+use crate::{HapqFeatures, Winner};
+
+//lower bounds of the bins of a new calibration table: score difference in bases, splits and read length
+const DIFF_BINS: [f32; 8] = [0.0, 1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 50.0];
+const SPLIT_BINS: [u32; 5] = [1, 2, 3, 4, 7];
+const LEN_BINS: [u32; 4] = [0, 1_000, 10_000, 50_000];
+
+const TABLE_HEADER: &str = "diff_min\tdiff_max\tsplits_min\tsplits_max\tlen_min\tlen_max\treads\terrors\thapq";
+
+//one bin of a calibration table, its ranges include their min and exclude their max
+struct Bin {
+    //None for reads mapped to the winning input only
+    diff: Option<(f32, f32)>,
+    splits: (u32, u32),
+    len: (u32, u32),
+    reads: u64,
+    errors: u64,
+    //HapQ of the bin's error rate, None for bins without reads
+    hapq: Option<u8>,
+}
+
+impl Bin {
+    fn contains(&self, f: &HapqFeatures) -> bool {
+        let diff = match (self.diff, f.diff) {
+            (None, None) => true,
+            (Some((min, max)), Some(d)) => min <= d && d < max,
+            _ => false,
+        };
+        diff && self.splits.0 <= f.splits && f.splits < self.splits.1 && self.len.0 <= f.read_len && f.read_len < self.len.1
+    }
+}
+
+//HapQ per bin of score difference, splits and read length, fitted from reads of known origin (--calibrate)
+//and looked up instead of the compute_hapq formula (--hapq-table)
+pub(crate) struct CalibrationTable {
+    bins: Vec<Bin>,
+}
+
+impl CalibrationTable {
+    //table of the default bins without reads, one group of difference bins (and one-sided reads) per splits and length bin
+    fn new() -> Self {
+        let mut bins = Vec::new();
+        for &len in ranges(&LEN_BINS, u32::MAX).iter() {
+            for &splits in ranges(&SPLIT_BINS, u32::MAX).iter() {
+                let diffs = ranges(&DIFF_BINS, f32::INFINITY).into_iter().map(Some).chain([None]);
+                bins.extend(diffs.map(|diff| Bin { diff, splits, len, reads: 0, errors: 0, hapq: None }));
+            }
+        }
+        CalibrationTable { bins }
+    }
+
+    //read a table written by --calibrate; its bins may have been edited, the first bin holding a read is used
+    pub(crate) fn load(path: &str) -> Result<Self, String> {
+        let reader = open_text(path).map_err(|e| format!("Failed to open HapQ table '{}': {}", path, e))?;
+        let mut bins = Vec::new();
+        for (n, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| format!("Failed to read HapQ table '{}': {}", path, e))?;
+            if line.is_empty() || line.starts_with('#') || line == TABLE_HEADER { continue; }
+            let bad = |what: &str| format!("Invalid {} on line {} of HapQ table '{}'", what, n + 1, path);
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() != 9 {
+                return Err(format!("Line {} of HapQ table '{}' has {} columns, expected 9 ({})", n + 1, path, fields.len(), TABLE_HEADER.replace('\t', ",")));
+            }
+            let diff = match (fields[0], fields[1]) {
+                ("NA", "NA") => None,
+                (min, max) => Some((min.parse().map_err(|_| bad("diff_min"))?, max.parse().map_err(|_| bad("diff_max"))?)),
+            };
+            let bound = |s: &str, what: &str| if s == "inf" { Ok(u32::MAX) } else { s.parse().map_err(|_| bad(what)) };
+            bins.push(Bin {
+                diff,
+                splits: (bound(fields[2], "splits_min")?, bound(fields[3], "splits_max")?),
+                len: (bound(fields[4], "len_min")?, bound(fields[5], "len_max")?),
+                reads: fields[6].parse().map_err(|_| bad("reads"))?,
+                errors: fields[7].parse().map_err(|_| bad("errors"))?,
+                hapq: match fields[8] {
+                    "NA" => None,
+                    q => Some(q.parse::<u8>().map_err(|_| bad("hapq"))?.min(60)),
+                },
+            });
+        }
+        if bins.iter().all(|b| b.hapq.is_none()) {
+            return Err(format!("HapQ table '{}' has no bin with a HapQ", path));
+        }
+        Ok(CalibrationTable { bins })
+    }
+
+    //HapQ of the bin of a read, None if it falls in no bin or one without reads
+    fn lookup(&self, f: &HapqFeatures) -> Option<u8> {
+        self.bins.iter().find(|b| b.contains(f)).and_then(|b| b.hapq)
+    }
+
+    fn add(&mut self, f: &HapqFeatures, correct: bool) {
+        if let Some(bin) = self.bins.iter_mut().find(|b| b.contains(f)) {
+            bin.reads += 1;
+            if !correct { bin.errors += 1; }
+        }
+    }
+
+    //HapQ of every bin from its error rate, with the difference bins of each splits and length bin pooled where needed
+    //so that HapQ never falls as the difference grows
+    fn fit(&mut self) {
+        let mut start = 0;
+        while start < self.bins.len() {
+            let (splits, len) = (self.bins[start].splits, self.bins[start].len);
+            let end = start + self.bins[start..].iter().take_while(|b| b.splits == splits && b.len == len).count();
+            fit_group(&mut self.bins[start..end]);
+            start = end;
+        }
+    }
+
+    fn write(&self, path: &str) -> std::io::Result<()> {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        writeln!(out, "{}", TABLE_HEADER)?;
+        let bound = |b: u32| if b == u32::MAX { "inf".to_string() } else { b.to_string() };
+        for bin in self.bins.iter() {
+            let (dmin, dmax) = match bin.diff {
+                Some((min, max)) => (min.to_string(), max.to_string()),
+                None => ("NA".to_string(), "NA".to_string()),
+            };
+            writeln!(out, "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}", dmin, dmax, bound(bin.splits.0), bound(bin.splits.1),
+                bound(bin.len.0), bound(bin.len.1), bin.reads, bin.errors, bin.hapq.map_or("NA".to_string(), |q| q.to_string()))?;
+        }
+        out.flush()
+    }
+}
+
+//(min, max) of every bin from the lower bounds, the last one open-ended
+fn ranges<T: Copy>(mins: &[T], max: T) -> Vec<(T, T)> {
+    mins.iter().zip(mins[1..].iter().chain([&max])).map(|(&a, &b)| (a, b)).collect()
+}
+
+//pool adjacent violators over the bins of one group, in order of increasing difference: a bin whose error rate is above
+//the one before is pooled with it, and every bin gets the HapQ of its pool; a pseudocount keeps bins without errors finite
+//one-sided reads are not ordered against the others and keep their own rate
+fn fit_group(bins: &mut [Bin]) {
+    let rate = |reads: u64, errors: u64| (errors as f64 + 0.5) / (reads as f64 + 1.0);
+    let phred = |rate: f64| (-10.0 * rate.log10()).round().clamp(0.0, 60.0) as u8;
+    //pools as (reads, errors, bins)
+    let mut pools: Vec<(u64, u64, Vec<usize>)> = Vec::new();
+    let filled: Vec<usize> = (0..bins.len()).filter(|&i| bins[i].reads > 0).collect();
+    for i in filled {
+        if bins[i].diff.is_none() {
+            bins[i].hapq = Some(phred(rate(bins[i].reads, bins[i].errors)));
+            continue;
+        }
+        pools.push((bins[i].reads, bins[i].errors, vec![i]));
+        while pools.len() > 1 {
+            let (last, prev) = (&pools[pools.len() - 1], &pools[pools.len() - 2]);
+            if rate(last.0, last.1) <= rate(prev.0, prev.1) { break; }
+            let (reads, errors, members) = pools.pop().unwrap();
+            let prev = pools.last_mut().unwrap();
+            prev.0 += reads;
+            prev.1 += errors;
+            prev.2.extend(members);
+        }
+    }
+    for (reads, errors, members) in pools {
+        for i in members {
+            bins[i].hapq = Some(phred(rate(reads, errors)));
+        }
+    }
+}
+
+//origin of reads of known haplotype (--truth), as the index of its label
+enum Truth {
+    //the first label contained in the read name
+    Names(Vec<String>),
+    //read name and label from a TSV
+    Table(HashMap<Vec<u8>, usize>),
+}
+
+impl Truth {
+    fn load(source: &str, labels: &[String]) -> Result<Self, String> {
+        if source == "name" {
+            return Ok(Truth::Names(labels.to_vec()));
+        }
+        let reader = open_text(source).map_err(|e| format!("Failed to open truth file '{}': {}", source, e))?;
+        let mut origins = HashMap::new();
+        for (n, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| format!("Failed to read truth file '{}': {}", source, e))?;
+            if line.is_empty() || line.starts_with('#') { continue; }
+            let mut fields = line.split('\t');
+            let (Some(name), Some(label)) = (fields.next(), fields.next()) else {
+                return Err(format!("Line {} of truth file '{}' needs a read name and a label", n + 1, source));
+            };
+            let i = labels.iter().position(|l| l == label)
+                .ok_or_else(|| format!("Label '{}' on line {} of truth file '{}' is none of {:?}", label, n + 1, source, labels))?;
+            origins.insert(name.as_bytes().to_vec(), i);
+        }
+        Ok(Truth::Table(origins))
+    }
+
+    fn origin(&self, qname: &[u8]) -> Option<usize> {
+        match self {
+            Truth::Names(labels) => labels.iter().position(|l| qname.windows(l.len()).any(|w| w == l.as_bytes())),
+            Truth::Table(origins) => origins.get(qname).copied(),
+        }
+    }
+}
+
+//HapQ calibration of one run: HapQ looked up in the table of the HapQ model (--hapq-table, or table of --hapq-model), and reads of known origin tallied into a new table (--calibrate)
+pub(crate) struct Calibration {
+    table: Option<CalibrationTable>,
+    tally: Option<(Truth, CalibrationTable)>,
+    //reads won by score whose origin is not known
+    unknown: u64,
+}
+
+impl Calibration {
+    pub(crate) fn new(args: &Cli, labels: &[String]) -> Result<Self, String> {
+        let table = args.model.table.as_deref().map(CalibrationTable::load).transpose()?;
+        let tally = match &args.truth {
+            Some(source) => Some((Truth::load(source, labels)?, CalibrationTable::new())),
+            None => None,
+        };
+        Ok(Calibration { table, tally, unknown: 0 })
+    }
+
+    //HapQ features of a read, only computed if a table is used or filled
+    pub(crate) fn features(&self, scores: &[Option<(f32, u32)>], winner: &Winner, match_sc: f32, read_len: impl FnOnce(usize) -> u32) -> Option<HapqFeatures> {
+        if self.table.is_none() && self.tally.is_none() { return None; }
+        match winner {
+            Winner::Best(w) => crate::hapq_features(scores, winner, match_sc, read_len(*w)),
+            _ => None,
+        }
+    }
+
+    //HapQ of a read won by score, from the table if its bin has one (up to the model's cap)
+    pub(crate) fn hapq(&self, features: Option<HapqFeatures>, hapq: Option<u8>, cap: u8) -> Option<u8> {
+        match (&self.table, features, hapq) {
+            (Some(table), Some(f), Some(_)) => table.lookup(&f).map(|q| q.min(cap)).or(hapq),
+            _ => hapq,
+        }
+    }
+
+    //count a read won by score as correctly or wrongly assigned, if its origin is known
+    pub(crate) fn record(&mut self, qname: &[u8], winner: &Winner, features: Option<HapqFeatures>) {
+        let (Some((truth, table)), Winner::Best(w), Some(f)) = (&mut self.tally, winner, features) else { return };
+        match truth.origin(qname) {
+            Some(origin) => table.add(&f, origin == *w),
+            None => self.unknown += 1,
+        }
+    }
+
+    //fit and write the table of --calibrate
+    pub(crate) fn finish(self, args: &Cli) -> Result<(), String> {
+        let (Some((_, mut table)), Some(path)) = (self.tally, &args.calibrate) else { return Ok(()) };
+        table.fit();
+        table.write(path).map_err(|e| format!("Failed to write HapQ table '{}': {}", path, e))?;
+        let (reads, errors) = table.bins.iter().fold((0, 0), |(r, e), b| (r + b.reads, e + b.errors));
+        eprintln!("HapQ calibration: {} reads of known origin, {} assigned to the wrong haplotype ({:.2}%), table written to {}",
+            reads, errors, errors as f64 / reads.max(1) as f64 * 100.0, path);
+        if self.unknown > 0 {
+            eprintln!("Warning: {} reads assigned by score have no known origin in --truth and were not used for calibration", self.unknown);
+        }
+        Ok(())
+    }
+}
+
+//presets of --hapq-model as (name, slope, split threshold, cap, one-sided HapQ); hifi is the formula's own constants
+const PRESETS: [(&str, f32, u32, u8, u8); 3] = [
+    ("hifi", 6.02, 3, 60, 60),
+    //noisier reads: a base of score difference says less, and ultra-long reads split more often
+    ("ont", 3.01, 5, 60, 60),
+    //short reads rarely split, so a split read is already suspect
+    ("illumina", 6.02, 1, 60, 60),
+];
+
+//parameters of the HapQ formula (compute_hapq), from a preset or a model file (--hapq-model)
+#[derive(Debug, Clone)]
+pub struct HapqModel {
+    //preset name or model file, recorded in the output header
+    pub source: String,
+    //HapQ per base of score difference
+    pub slope: f32,
+    //splits beyond which HapQ is scaled by threshold/splits
+    pub split_threshold: u32,
+    //highest HapQ given
+    pub cap: u8,
+    //HapQ of reads mapped to one input only
+    pub one_sided: u8,
+    //calibration table looked up instead of the formula (see --hapq-table)
+    pub table: Option<String>,
+}
+
+impl Default for HapqModel {
+    fn default() -> Self {
+        HapqModel::preset("hifi").unwrap()
+    }
+}
+
+//a model file as written, TOML or JSON: every key is optional and unknown keys are an error
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ModelFile {
+    preset: Option<String>,
+    slope: Option<f32>,
+    split_threshold: Option<u32>,
+    cap: Option<u8>,
+    one_sided: Option<u8>,
+    table: Option<String>,
+}
+
+impl HapqModel {
+    fn preset(name: &str) -> Option<Self> {
+        PRESETS.iter().find(|p| p.0 == name).map(|&(name, slope, split_threshold, cap, one_sided)| {
+            HapqModel { source: name.to_string(), slope, split_threshold, cap, one_sided, table: None }
+        })
+    }
+
+    //a preset name, or a model file: TOML or a JSON object, with the keys preset (the parameters not given, default hifi),
+    //slope, split_threshold, cap, one_sided and table (relative to the model file)
+    pub fn load(source: &str) -> Result<Self, String> {
+        if let Some(model) = HapqModel::preset(source) {
+            return Ok(model);
+        }
+        let text = std::fs::read_to_string(source).map_err(|e| format!("--hapq-model '{}' is no preset ({}) and cannot be read: {}",
+            source, PRESETS.map(|p| p.0).join(", "), e))?;
+        HapqModel::parse(&text, source)
+    }
+
+    //JSON if the file ends in .json, TOML if in .toml, else JSON if it starts with '{'
+    fn parse(text: &str, source: &str) -> Result<Self, String> {
+        let lower = source.to_ascii_lowercase();
+        let json = if lower.ends_with(".json") {
+            true
+        } else if lower.ends_with(".toml") {
+            false
+        } else {
+            text.trim_start().starts_with('{')
+        };
+        let file: ModelFile = if json {
+            serde_json::from_str(text).map_err(|e| format!("Invalid HapQ model '{}' (JSON): {}", source, e))?
+        } else {
+            //the line of a TOML error, in one line like serde_json's
+            toml::from_str(text).map_err(|e| {
+                let line = e.span().map_or(String::new(), |span| format!(" at line {}", text[..span.start].matches('\n').count() + 1));
+                format!("Invalid HapQ model '{}' (TOML): {}{}", source, e.message().trim_end(), line)
+            })?
+        };
+
+        let mut model = match &file.preset {
+            Some(preset) => HapqModel::preset(preset)
+                .ok_or_else(|| format!("Unknown preset '{}' in HapQ model '{}' (presets: {})", preset, source, PRESETS.map(|p| p.0).join(", ")))?,
+            None => HapqModel::default(),
+        };
+        model.source = source.to_string();
+        let bad = |key: &str, value: &dyn std::fmt::Display| format!("Invalid {} '{}' in HapQ model '{}'", key, value, source);
+        if let Some(slope) = file.slope {
+            if !slope.is_finite() || slope <= 0.0 { return Err(bad("slope", &slope)); }
+            model.slope = slope;
+        }
+        if let Some(threshold) = file.split_threshold {
+            if threshold < 1 { return Err(bad("split_threshold", &threshold)); }
+            model.split_threshold = threshold;
+        }
+        if let Some(cap) = file.cap {
+            if cap > 60 { return Err(bad("cap", &cap)); }
+            model.cap = cap;
+        }
+        if let Some(one_sided) = file.one_sided {
+            if one_sided > 60 { return Err(bad("one_sided", &one_sided)); }
+            model.one_sided = one_sided;
+        }
+        if let Some(table) = &file.table {
+            let dir = std::path::Path::new(source).parent().unwrap_or(std::path::Path::new(""));
+            model.table = Some(dir.join(table).to_string_lossy().into_owned());
+        }
+        Ok(model)
+    }
+
+    //@CO line text recording the model in SAM/BAM/CRAM output
+    pub(crate) fn header_comment(&self) -> String {
+        format!("diplinator HapQ model {}: slope={} split_threshold={} cap={} one_sided={} table={}",
+            self.source, self.slope, self.split_threshold, self.cap, self.one_sided, self.table.as_deref().unwrap_or("none"))
+    }
+}
+
+impl Cli {
+    //set the HapQ model of --hapq-model, with the table of --hapq-table if given
+    pub fn resolve_hapq_model(&mut self) -> Result<(), String> {
+        if let Some(source) = &self.hapq_model {
+            self.model = HapqModel::load(source)?;
+        }
+        if let Some(table) = &self.hapq_table {
+            self.model.table = Some(table.clone());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn model_file_keys_and_comments() {
+        let text = "# ONT R10\npreset = \"hifi\"\nslope = 4.5 # fitted\n\ncap=50\nsplit_threshold = 5\none_sided = 40\ntable = \"fits/a,b:c.tsv\" # quoted\n";
+        let model = HapqModel::parse(text, "models/ont.toml").unwrap();
+        assert_eq!(model.source, "models/ont.toml");
+        assert_eq!(model.slope, 4.5);
+        assert_eq!(model.split_threshold, 5);
+        assert_eq!(model.cap, 50);
+        assert_eq!(model.one_sided, 40);
+        assert_eq!(model.table.as_deref(), Some("models/fits/a,b:c.tsv"));
+    }
+
+    #[test]
+    fn model_file_defaults_to_hifi() {
+        let model = HapqModel::parse("cap = 30\n", "m.toml").unwrap();
+        let hifi = HapqModel::default();
+        assert_eq!((model.slope, model.split_threshold, model.one_sided, model.cap), (hifi.slope, hifi.split_threshold, hifi.one_sided, 30));
+        assert!(model.table.is_none());
+    }
+
+    #[test]
+    fn model_file_as_json() {
+        //the format comes from the extension, else from the first byte
+        for source in ["m.json", "model"] {
+            let model = HapqModel::parse("\n  {\"preset\": \"ont\", \"slope\": 4, \"table\": \"t.tsv\"}\n", source).unwrap();
+            assert_eq!((model.slope, model.split_threshold, model.table.as_deref()), (4.0, 5, Some("t.tsv")));
+        }
+        assert!(HapqModel::parse("{\"slope\": 4, \"colour\": 1}", "m.json").is_err());
+        assert!(HapqModel::parse("slope = 4\n", "m.json").is_err());
+    }
+
+    #[test]
+    fn model_file_presets() {
+        let ont = HapqModel::parse("preset = \"ont\"\n", "m.toml").unwrap();
+        assert_eq!((ont.slope, ont.split_threshold, ont.cap, ont.one_sided), (3.01, 5, 60, 60));
+        let illumina = HapqModel::load("illumina").unwrap();
+        assert_eq!((illumina.source.as_str(), illumina.slope, illumina.split_threshold), ("illumina", 6.02, 1));
+    }
+
+    #[test]
+    fn model_file_rejects_unknown_syntax() {
+        for text in [
+            "[hapq]\nslope = 4\n",
+            "slope\n",
+            "slope = [1, 2]\n",
+            "slope = 4 5\n",
+            "preset = \"hifi\n",
+            "slope = 4\nslope = 5\n",
+            "bad key = 1\n",
+        ] {
+            assert!(HapqModel::parse(text, "m.toml").is_err(), "accepted {:?}", text);
+        }
+    }
+
+    #[test]
+    fn model_file_checks_keys_and_values() {
+        for text in ["colour = 1\n", "slope = 0\n", "slope = \"4\"\n", "cap = 61\n", "cap = 300\n", "split_threshold = 0\n", "preset = \"nanopore\"\n", "preset = hifi\n", "table = 1\n"] {
+            assert!(HapqModel::parse(text, "m.toml").is_err(), "accepted {:?}", text);
+        }
+    }
+
+    #[test]
+    fn model_file_errors_name_the_line() {
+        let err = HapqModel::parse("cap = 50\n\n[section]\n", "m.toml").unwrap_err();
+        assert!(err.contains("line 3"), "{}", err);
+    }
+}
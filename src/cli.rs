@@ -1,62 +1,542 @@
+
 use clap::{Parser, ValueEnum};
 
+use crate::HapqModel;
+
 
 #[derive(Parser, Debug)]
 #[command( name = "Diplinator", about = "Diplinator: Choose the best alignment to each haploid of a diploid assembly", version)]
 
 pub struct Cli {
-    //
-    #[arg(value_name = "ASM1", help="asm1 alignment file (sam/bam/cram/paf)")]
-    pub asm1: String,
+    //one alignment file per haplotype/reference, all name-sorted in the same order
+    //or a single file aligned to the concatenated diploid reference with --hap-map/--hap-tags
+    #[arg(value_name = "ALN", num_args = 1.., required = true, help="alignment files, one per haplotype/reference (sam/bam/cram/paf, '-' for stdin), or one file with --hap-map/--hap-tags")]
+    pub inputs: Vec<String>,
+
+    #[arg(short='l', long, value_name = "NAME,...", value_delimiter = ',', help="labels for all inputs, in input order (used in output file names and summary) [default: asm1,asm2,...]")]
+    pub labels: Vec<String>,
 
-    #[arg(value_name = "ASM2", help="asm2 alignment file (sam/bam/cram/paf)")]
-    pub asm2: String,
+    #[arg(short='1', long, value_name = "NAME", help="label for the first input (overrides first --labels entry)")]
+    pub s1: Option<String>,
 
-    #[arg(short='1', long, value_name = "NAME", default_value = "asm1", help="label for asm1 sample (used in output file names and summary)")]
-    pub s1: String,
+    #[arg(short='2', long, value_name = "NAME", help="label for the second input (overrides second --labels entry)")]
+    pub s2: Option<String>,
 
-    #[arg(short='2', long, value_name = "NAME", default_value = "asm2", help="label for asm2 sample (used in output file names and summary)")]
-    pub s2: String,
+    // write everything to one stream rather than one file per input
+    #[arg(short='o', long, value_name = "FILE", help="write all assigned reads to one merged file instead of one file per input ('-' for stdout)")]
+    pub merged: Option<String>,
+
+    // contig names of different inputs can collide in the merged header
+    #[arg(long, value_name = "PREFIX,...", value_delimiter = ',', requires = "merged", conflicts_with_all = ["hap_map", "hap_tags"], help = "prefixes for contig names found in several inputs of merged output, in input order [default: <label>#]")]
+    pub contig_prefixes: Vec<String>,
 
     // inputs are PAF files
     #[arg(long, default_value_t = false, help = "input files are PAF")]
     pub paf: bool,
 
+    // inputs are GAF files
+    #[arg(long, default_value_t = false, conflicts_with = "paf", help = "input files are GAF (graph alignments, e.g. from minigraph or GraphAligner)")]
+    pub gaf: bool,
+
+    // per-input formats for mixed inputs
+    #[arg(long, value_name = "FMT,...", value_delimiter = ',', conflicts_with_all = ["paf", "gaf"], help = "format of every input, in input order: auto, sam (SAM/BAM/CRAM), paf or gaf [default: auto, from the file name and content]")]
+    pub formats: Vec<InputFormat>,
+
+    #[arg(long, value_name = "FMT", help = "output format for every output file: sam, bam, cram, paf or gaf, or fastq/ubam (unaligned BAM) to bin the reads themselves [default: format of each input]")]
+    pub out_format: Option<OutFormat>,
+
+    #[arg(long, value_name = "TAG,...", value_delimiter = ',', default_value = "MM,ML", help = "tags of the primary record kept in FASTQ/unaligned BAM output, besides hq/hl")]
+    pub bin_tags: Vec<String>,
+
+    // compress PAF output
+    #[arg(long, default_value_t = false, help = "write BGZF-compressed PAF/GAF/FASTQ output (.paf.gz/.gaf.gz/.fastq.gz)")]
+    pub bgzf: bool,
+
+    // where per-input outputs are written and how they are named
+    #[arg(long, value_name = "DIR", conflicts_with_all = ["merged", "outputs"], help = "directory for the per-input output files, created if missing [default: current directory]")]
+    pub out_dir: Option<String>,
+
+    #[arg(long, value_name = "STR", default_value = "diplinator_", conflicts_with_all = ["merged", "outputs"], help = "prefix of the per-input output file names, followed by the input label and extension")]
+    pub prefix: String,
+
+    #[arg(long, value_name = "FILE,...", value_delimiter = ',', conflicts_with = "merged", help = "explicit output file for every input, in input order; the format follows the extension unless --out-format is given")]
+    pub outputs: Vec<String>,
+
+    // writer settings
+    #[arg(long, value_name = "INT", value_parser = clap::value_parser!(u8).range(0..=9), help = "compression level of BAM/CRAM and BGZF PAF/GAF output, 0-9 [default: htslib default]")]
+    pub level: Option<u8>,
+
+    #[arg(long, value_name = "VER", help = "CRAM version of CRAM output, e.g. 3.0 or 3.1 [default: htslib default]")]
+    pub cram_version: Option<String>,
+
+    #[arg(long, default_value_t = false, help = "embed the reference sequence in CRAM output, so it can be decoded without the FASTA")]
+    pub embed_ref: bool,
+
+    // coordinate-sorted output, so no samtools sort/index pass is needed afterwards
+    #[arg(long, default_value_t = false, help = "write coordinate-sorted output (PAF/GAF by target name and position) and index BAM/CRAM output (.bai/.crai); memory per output is --sort-mem")]
+    pub sort_output: bool,
+
+    #[arg(long, default_value_t = false, requires = "sort_output", help = "index sorted BAM output with .csi instead of .bai (automatic for contigs of 512 Mbp and longer)")]
+    pub csi: bool,
+
     //use ms score rather than AS score
-    #[arg(long, default_value_t = false, help = "use ms:i: tag rather than AS:i: for alignment score")]
+    #[arg(long, default_value_t = false, help = "use ms:i: tag rather than AS:i: for alignment score (same as --score ms)")]
     pub ms: bool,
 
-    // write tied reads to both output files
-    #[arg(short, long, default_value_t = false, help = "write reads with equal alignment scores to both output files")]
+    // scores of aligners and converters that write no AS tag
+    #[arg(long, value_name = "SOURCE", default_value = "AS", value_parser = parse_score_source, conflicts_with = "ms", help = "alignment score of every record: an integer or float tag (e.g. AS, ms, s1), or derived with --score-scheme from nm (NM tag and CIGAR), de (de:f: divergence and CIGAR), cs (cs tag) or cigar (=/X operations); PAF/GAF alignments give their CIGAR in cg:Z:; ref recomputes it from CIGAR, SEQ and the reference FASTA (--refs)")]
+    pub score: ScoreSource,
+
+    #[arg(long, value_name = "A,B,O,E", default_value = "2,4,4,2", value_parser = parse_score_scheme, help = "scoring scheme of derived scores: match score, mismatch penalty, gap open and gap extension penalty (as minimap2 -A/-B/-O/-E, a gap of length k costs O+k*E)")]
+    pub score_scheme: ScoreScheme,
+
+    // paired-end reads: both mates share a read name, so they are scored per mate and assigned together
+    #[arg(long, default_value_t = false, conflicts_with_all = ["paf", "gaf"], help = "paired-end reads: score READ1 and READ2 separately and assign each fragment by the sum of its mate scores (HAPQ per fragment)")]
+    pub paired: bool,
+
+    #[arg(long, value_name = "FLOAT", default_value_t = 0.0, requires = "paired", help = "score added to fragments whose mates align as a proper pair (flag 0x2) to the same contig")]
+    pub pair_bonus: f32,
+
+    // split alignments scattered over the genome are less plausible than one collinear chain
+    #[arg(long, default_value_t = false, help = "penalize reads whose split alignments do not form one collinear chain (see --chain-penalties) and tag records with their chain (hc:Z:)")]
+    pub chain: bool,
+
+    #[arg(long, value_name = "C,S,B,O", default_value = "200,200,100,50", value_parser = parse_chain_penalties, requires = "chain", help = "score subtracted per junction between split alignments: to another contig, to the other strand, backwards on the contig, overlapping on the contig")]
+    pub chain_penalties: ChainPenalties,
+
+    // the assignment signal sits at the sites where the haplotypes differ, not in whole-read scores
+    #[arg(long, value_name = "VCF,...", value_delimiter = ',', conflicts_with_all = ["paf", "gaf"], help = "heterozygous sites between the two haplotypes, REF being the allele of the haplotype whose contig the site is on: one VCF per input in input order (an empty entry skips an input), or one for --hap-map/--hap-tags; reads covering sites are assigned by the base qualities of the sites supporting each haplotype and tagged with them (hs:i: supporting, hx:i: conflicting)")]
+    pub het_vcf: Vec<String>,
+
+    // write tied reads to all tied output files
+    #[arg(short, long, default_value_t = false, help = "write reads with equal alignment scores to all tied output files")]
     pub both: bool,
 
-    // where to write reads unmapped in both assemblies
-    #[arg(short, long, value_name = "DEST", default_value = "asm1", help="where to write reads unmapped in both assemblies: asm1, asm2, or discard")]
+    // ties resolved on the alignments before randomness
+    #[arg(long, value_name = "CRITERION,...", value_delimiter = ',', help = "break ties in score by these criteria in order, before --tie-prefer or the read name hash: splits (fewer non-secondary alignments), mapq (higher primary MAPQ), nm (lower NM), span (more read bases aligned); tied reads keep HapQ 0")]
+    pub tie_break: Vec<TieCriterion>,
+
+    #[arg(long, value_name = "DEST", conflicts_with = "both", help = "write reads still tied to this input (a label or asmN) when it is among the tied ones, instead of one picked by read name hash")]
+    pub tie_prefer: Option<String>,
+
+    #[arg(long, value_name = "INT", default_value_t = 42, conflicts_with = "both", help = "seed of the read name hash that picks the output of tied reads")]
+    pub tie_seed: u64,
+
+    #[arg(long, value_name = "BP", value_parser = clap::value_parser!(u64).range(1..), conflicts_with_all = ["both", "tie_prefer"], help = "write reads still tied to the input with the fewest reads starting in the same window of this size on its contig, so that tied reads even out depth along every haplotype (deterministic for a given input)")]
+    pub tie_balance: Option<u64>,
+
+    // where to write reads unmapped in all inputs
+    #[arg(short, long, value_name = "DEST", default_value = "asm1", help="where to write reads unmapped in all inputs: an input label, asmN for the Nth input, or discard")]
+    pub unmapped: String,
+
+    // single alignment against the concatenated diploid reference
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["paf", "gaf", "formats", "merge_join"], help = "single input aligned to the concatenated diploid reference (keep secondaries, e.g. minimap2 -N): TSV of contig name and haplotype label")]
+    pub hap_map: Option<String>,
 
-    pub unmapped: UnmappedDest,
+    #[arg(long, value_name = "TAG,...", value_delimiter = ',', conflicts_with_all = ["hap_map", "paf", "gaf", "formats", "merge_join"], help = "like --hap-map, but the haplotype of a contig is the first TAG contained in its name (as in separate_haps_fasta)")]
+    pub hap_tags: Vec<String>,
 
-    #[arg(long, value_name = "FILE", required = false, help="reference FASTA for cram file (asm1)")]
+    // run the aligner for every input
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["paf", "gaf", "formats", "hap_map", "hap_tags"], help = "align these reads to every input with --aligner and assign them on the fly (inputs are then haplotype FASTAs)")]
+    pub reads: Option<String>,
+
+    #[arg(long, value_name = "CMD", default_value = "minimap2 -ax map-hifi -t {threads} {ref} {reads}", help = "aligner command for --reads, run once per input FASTA; {ref}, {reads} and {threads} are replaced and it must write read-grouped SAM to stdout")]
+    pub aligner: String,
+
+    #[arg(long, value_name = "FILE,...", value_delimiter = ',', help="reference FASTAs for cram files and --score ref, in input order [default: found from the @SQ M5/UR fields, see --ref-dir]")]
+    pub refs: Vec<String>,
+
+    #[arg(long, value_name = "FILE", required = false, help="reference FASTA for cram file (first input, overrides first --refs entry)")]
     pub ref1: Option<String>,
 
-    #[arg(long, value_name = "FILE", required = false, help="reference FASTA for cram file (asm2)")]
+    #[arg(long, value_name = "FILE", required = false, help="reference FASTA for cram file (second input, overrides second --refs entry)")]
     pub ref2: Option<String>,
 
+    #[arg(long, value_name = "FILE,...", value_delimiter = ',', help="reference FASTAs for CRAM output, in output order [default: the input's reference]")]
+    pub out_refs: Vec<String>,
+
+    // CRAM references not given explicitly are looked up by the @SQ M5 checksum (REF_CACHE/REF_PATH) or UR path
+    #[arg(long, value_name = "DIR", help="directory of FASTAs searched for CRAM references not given with --refs/--out-refs: every contig is matched by its @SQ M5 checksum (else name and length), in one FASTA or across several")]
+    pub ref_dir: Option<String>,
+
+    // tolerate reads missing from some inputs
+    #[arg(long, default_value_t = false, help = "merge-join inputs by read name, treating reads missing from an input as unmapped there (inputs are name-sorted first if needed)")]
+    pub merge_join: bool,
+
+    #[arg(long, value_name = "ORDER", help = "read name order of name-sorted inputs: natural (samtools sort -n) or lexicographic (samtools sort -N, LC_ALL=C sort) [default: from @HD SO/SS, else natural]")]
+    pub name_order: Option<NameOrder>,
+
+    // memory-bounded grouping of coordinate-sorted input by read name
+    #[arg(long, value_name = "SIZE", default_value = "768M", value_parser = parse_size, help = "memory per input for grouping coordinate-sorted input by read name (and per output with --sort-output), beyond which records are spilled to --tmp-dir (K/M/G suffix)")]
+    pub sort_mem: usize,
+
+    #[arg(long, value_name = "DIR", help = "directory for temporary sort files and references combined from --ref-dir [default: system temp dir]")]
+    pub tmp_dir: Option<String>,
+
     // per-base match score from aligner scoring scheme (used in HAPQ calculation)
     #[arg(long, value_name = "FLOAT", default_value_t = 2.0, help = "per-base match score from aligner scoring scheme (e.g. minimap2 default is 2.0 for long reads)")]
     pub match_sc: f32,
 
+    // machine-readable summary
+    #[arg(long, value_name = "FILE", help = "also write the summary as TSV: read counts and HapQ distribution overall, per read group (RG tag) and per sample (@RG SM) if there are several")]
+    pub summary_tsv: Option<String>,
+
+    // a read placed ambiguously within the winning input is no more certain than that placement
+    #[arg(long, value_name = "SIGNAL,...", value_delimiter = ',', conflicts_with = "no_hapq", help = "cap HapQ by how uniquely the read is placed within the winning input: mapq (MAPQ), s2 (minimap2 s1:i vs s2:i), xs (BWA AS:i vs XS:i), secondary (best secondary vs primary score); only secondary with --hap-map/--hap-tags")]
+    pub hapq_multimap: Vec<MultimapSignal>,
+
+    // check HapQ against reads of known origin, and use what was observed
+    #[arg(long, value_name = "FILE", requires = "truth", conflicts_with = "no_hapq", help = "fit a HapQ calibration table (TSV) from reads of known origin (--truth): reads assigned by score are binned by score difference, splits and read length, and every bin gets the HapQ of its observed error rate; use it with --hapq-table")]
+    pub calibrate: Option<String>,
+
+    #[arg(long, value_name = "FILE|name", requires = "calibrate", help = "origin of the reads for --calibrate: a TSV of read name and input/haplotype label, or 'name' for the first label contained in the read name")]
+    pub truth: Option<String>,
+
+    #[arg(long, value_name = "FILE", conflicts_with = "no_hapq", help = "HapQ calibration table written by --calibrate: reads assigned by score get the HapQ of their bin instead of the formula (bins without reads keep it)")]
+    pub hapq_table: Option<String>,
+
+    // HapQ constants differ between read types
+    #[arg(long, value_name = "PRESET|FILE", conflicts_with = "no_hapq", help = "HapQ model: a preset (hifi, the default; ont; illumina) or a TOML/JSON file with the keys preset, slope, split_threshold, cap, one_sided and table; recorded in an @CO line of SAM/BAM/CRAM output")]
+    pub hapq_model: Option<String>,
+
+    //model resolved from --hapq-model and --hapq-table, see Cli::resolve_hapq_model
+    #[arg(skip)]
+    pub model: HapqModel,
+
     // skip HAPQ score calculation and hq tag output (for non-haplotype comparisons)
     #[arg(long, default_value_t = false, help = "skip HAPQ score calculation and hq tag output (e.g. for comparing grch38 vs chm13)")]
     pub no_hapq: bool,
 
     // number of total threads to use
-    #[arg(short, long,value_name = "INT", default_value_t = 8, help = "Total thread pool size (min 4). Multiples of 8 recommended for optimal read/write balance.")]
+    #[arg(short, long,value_name = "INT", default_value_t = 8, help = "Total thread pool size (min 2 per input). Multiples of 8 recommended for optimal read/write balance.")]
     pub threads: usize
 }
 
-#[derive(Debug, Clone, ValueEnum)]
-pub enum UnmappedDest {
-    Asm1,
-    Asm2,
-    Discard,
+//collation of read names in name-sorted input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum NameOrder {
+    Natural,
+    Lexicographic,
+}
+
+//format of an input file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum InputFormat {
+    //detect from the file name and content
+    Auto,
+    //SAM, BAM or CRAM, whichever htslib detects
+    Sam,
+    Paf,
+    Gaf,
+}
+
+//format of an output file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutFormat {
+    Sam,
+    Bam,
+    Cram,
+    Paf,
+    Gaf,
+    //reads instead of alignments, binned by haplotype
+    Fastq,
+    Ubam,
+}
+
+//where the alignment score of a record comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScoreSource {
+    //integer or float tag holding the score
+    Tag(String),
+    //derived with the scoring scheme from the differences to the reference:
+    //NM edit distance and CIGAR gaps, de:f: gap-compressed divergence and CIGAR, the cs tag, or =/X CIGAR operations
+    Nm,
+    De,
+    Cs,
+    Cigar,
+    //recomputed from CIGAR and read sequence against the reference FASTA, whatever the aligner wrote
+    Ref,
+}
+
+//scoring scheme of derived scores, as minimap2 -A/-B/-O/-E
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreScheme {
+    pub matched: f64,
+    pub mismatch: f64,
+    pub gap_open: f64,
+    pub gap_ext: f64,
+}
+
+//signal of how uniquely a read is placed within one input, for --hapq-multimap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MultimapSignal {
+    Mapq,
+    S2,
+    Xs,
+    Secondary,
+}
+
+//criterion of --tie-break, comparing the alignments of a read to each tied input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TieCriterion {
+    Splits,
+    Mapq,
+    Nm,
+    Span,
+}
+
+//penalties of --chain per junction between consecutive split alignments of a read
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChainPenalties {
+    pub contig: f64,
+    pub strand: f64,
+    pub order: f64,
+    pub overlap: f64,
+}
+
+//parse a score source: nm, de, cs, cigar or ref (any case), else the name of a score tag
+fn parse_score_source(s: &str) -> Result<ScoreSource, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "nm" => return Ok(ScoreSource::Nm),
+        "de" => return Ok(ScoreSource::De),
+        "cs" => return Ok(ScoreSource::Cs),
+        "cigar" => return Ok(ScoreSource::Cigar),
+        "ref" => return Ok(ScoreSource::Ref),
+        _ => {}
+    }
+    let b = s.as_bytes();
+    if b.len() == 2 && b[0].is_ascii_alphabetic() && b[1].is_ascii_alphanumeric() {
+        Ok(ScoreSource::Tag(s.to_string()))
+    } else {
+        Err(format!("'{}' is not a two-character tag name, nm, de, cs, cigar or ref", s))
+    }
+}
+
+//parse four comma-separated non-negative numbers, e.g. 2,4,4,2; what names the list and its entries in errors
+fn parse_four(s: &str, what: &str) -> Result<[f64; 4], String> {
+    let values = s.split(',').map(|v| v.trim().parse::<f64>()).collect::<Result<Vec<f64>, _>>()
+        .map_err(|_| format!("invalid {} '{}'", what, s))?;
+    match values[..] {
+        [a, b, c, d] if values.iter().all(|v| *v >= 0.0) => Ok([a, b, c, d]),
+        _ => Err(format!("{} '{}' must be four non-negative numbers", what, s)),
+    }
+}
+
+//parse a scoring scheme A,B,O,E
+fn parse_score_scheme(s: &str) -> Result<ScoreScheme, String> {
+    let [matched, mismatch, gap_open, gap_ext] = parse_four(s, "scoring scheme A,B,O,E")?;
+    Ok(ScoreScheme { matched, mismatch, gap_open, gap_ext })
+}
+
+//parse chain penalties C,S,B,O
+fn parse_chain_penalties(s: &str) -> Result<ChainPenalties, String> {
+    let [contig, strand, order, overlap] = parse_four(s, "chain penalties C,S,B,O")?;
+    Ok(ChainPenalties { contig, strand, order, overlap })
+}
+
+//parse a byte count with an optional K/M/G suffix, e.g. 768M
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (num, mult) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1usize << 10),
+        Some('M') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    num.parse::<usize>().ok().and_then(|n| n.checked_mul(mult)).ok_or_else(|| format!("invalid size '{}'", s))
+}
+
+impl Cli {
+    //check input paths that cannot be known from clap alone
+    pub fn check_inputs(&self) -> Result<(), String> {
+        //stdin can only be read once
+        if self.inputs.iter().filter(|p| *p == "-").count() > 1 {
+            return Err("Only one input can be read from stdin ('-'); use named pipes or process substitution for the others".into());
+        }
+        if self.diploid_mode() {
+            if self.inputs.len() != 1 {
+                return Err("--hap-map/--hap-tags take a single alignment file against the concatenated diploid reference".into());
+            }
+            if !self.hap_tags.is_empty() && self.hap_tags.len() < 2 {
+                return Err("--hap-tags needs at least two tags".into());
+            }
+            //MAPQ, s2 and XS compare against the whole diploid reference, so they mostly reflect the other haplotype
+            if self.hapq_multimap.iter().any(|s| *s != MultimapSignal::Secondary) {
+                return Err("--hapq-multimap can only use secondary with --hap-map/--hap-tags, as MAPQ, s2 and XS compare against both haplotypes".into());
+            }
+            if self.het_vcf.len() > 1 {
+                return Err("--het-vcf takes a single VCF with --hap-map/--hap-tags, its sites are on the contigs of both haplotypes".into());
+            }
+        } else if self.inputs.len() < 2 {
+            return Err("At least two alignment files are needed (or one with --hap-map/--hap-tags)".into());
+        } else if !self.het_vcf.is_empty() && (self.inputs.len() != 2 || self.het_vcf.len() > 2) {
+            return Err(format!("--het-vcf compares two haplotypes: it needs exactly two inputs and at most one VCF per input, but {} inputs and {} VCFs were given",
+                self.inputs.len(), self.het_vcf.len()));
+        }
+        if let Some(reads) = &self.reads {
+            //every aligner reads the reads file, so it cannot be a one-shot stream
+            if reads == "-" || self.inputs.iter().any(|p| p == "-") {
+                return Err("--reads and the input FASTAs cannot be read from stdin ('-'), every aligner needs its own copy".into());
+            }
+            if !self.aligner.contains("{ref}") {
+                return Err("--aligner must contain {ref}, which is replaced by each input FASTA".into());
+            }
+        }
+        Ok(())
+    }
+
+    //true if the single input is aligned to the concatenated diploid reference
+    pub fn diploid_mode(&self) -> bool {
+        self.hap_map.is_some() || !self.hap_tags.is_empty()
+    }
+
+    //resolve the label of every input: --labels first, then -1/-2 overrides, then asmN defaults
+    pub fn input_labels(&self) -> Result<Vec<String>, String> {
+        let defaults: Vec<String> = (0..self.inputs.len()).map(|i| format!("asm{}", i + 1)).collect();
+        self.labels_for(&defaults, "input files")
+    }
+
+    //resolve labels for the given default names, with the same --labels and -1/-2 overrides as input labels
+    //what names the things being labelled in the error message
+    pub fn labels_for(&self, defaults: &[String], what: &str) -> Result<Vec<String>, String> {
+        if !self.labels.is_empty() && self.labels.len() != defaults.len() {
+            return Err(format!("--labels has {} entries but {} {} were given", self.labels.len(), defaults.len(), what));
+        }
+        let mut labels: Vec<String> = defaults.iter().enumerate()
+            .map(|(i, d)| self.labels.get(i).cloned().unwrap_or_else(|| d.clone()))
+            .collect();
+        if let Some(s1) = &self.s1 { labels[0] = s1.clone(); }
+        if let Some(s2) = &self.s2 { labels[1] = s2.clone(); }
+
+        //labels end up in output file names, so they must be unique
+        for (i, label) in labels.iter().enumerate() {
+            if labels[..i].contains(label) {
+                return Err(format!("Input label '{}' is used more than once", label));
+            }
+        }
+        Ok(labels)
+    }
+
+    //reference FASTA for the ith input, if any was given
+    pub fn reference(&self, i: usize) -> Option<&str> {
+        let over = match i {
+            0 => self.ref1.as_deref(),
+            1 => self.ref2.as_deref(),
+            _ => None,
+        };
+        over.or(self.refs.get(i).map(|s| s.as_str()))
+    }
+
+    //reference FASTA for CRAM output to the ith output: the --out-refs entry, else the ith input's reference
+    pub fn out_reference(&self, i: usize) -> Option<&str> {
+        self.out_refs.get(i).map(|s| s.as_str()).or(self.reference(i))
+    }
+
+    //score source after --ms, which is a shorthand for --score ms
+    pub fn score_source(&self) -> ScoreSource {
+        if self.ms { ScoreSource::Tag("ms".into()) } else { self.score.clone() }
+    }
+
+    //directory for temporary files
+    pub fn tmp_dir(&self) -> std::path::PathBuf {
+        match &self.tmp_dir {
+            Some(dir) => std::path::PathBuf::from(dir),
+            None => std::env::temp_dir(),
+        }
+    }
+
+    //path of every per-input (or per-haplotype) output, given their labels and extensions:
+    //the --outputs entries, else {--out-dir}/{--prefix}{label}{extension}
+    //--out-dir is created if it does not exist yet
+    pub fn output_paths(&self, labels: &[String], extensions: &[&str]) -> Result<Vec<String>, String> {
+        if !self.outputs.is_empty() {
+            if self.outputs.len() != labels.len() {
+                return Err(format!("--outputs has {} entries but there are {} outputs ({})", self.outputs.len(), labels.len(), labels.join(",")));
+            }
+            for (i, path) in self.outputs.iter().enumerate() {
+                if self.outputs[..i].contains(path) {
+                    return Err(format!("Output file '{}' is given more than once in --outputs", path));
+                }
+            }
+            return Ok(self.outputs.clone());
+        }
+        if let Some(dir) = &self.out_dir {
+            std::fs::create_dir_all(dir).map_err(|e| format!("Failed to create output directory '{}': {}", dir, e))?;
+        }
+        Ok(labels.iter().zip(extensions.iter()).map(|(label, ext)| {
+            let name = format!("{}{}{}", self.prefix, label, ext);
+            match &self.out_dir {
+                Some(dir) => std::path::Path::new(dir).join(name).to_string_lossy().into_owned(),
+                None => name,
+            }
+        }).collect())
+    }
+
+    //explicit path of the ith output (-o, or an --outputs entry), whose extension can name its format
+    pub fn named_output(&self, i: usize) -> Option<&str> {
+        match &self.merged {
+            Some(path) => Some(path.as_str()),
+            None => self.outputs.get(i).map(|s| s.as_str()),
+        }
+    }
+
+    //prefix of every input's contig names that collide in the merged header, --contig-prefixes or {label}#
+    pub fn contig_prefixes(&self, labels: &[String]) -> Result<Vec<String>, String> {
+        if self.contig_prefixes.is_empty() {
+            return Ok(labels.iter().map(|l| format!("{}#", l)).collect());
+        }
+        if self.contig_prefixes.len() != labels.len() {
+            return Err(format!("--contig-prefixes has {} entries but {} input files were given", self.contig_prefixes.len(), labels.len()));
+        }
+        Ok(self.contig_prefixes.clone())
+    }
+
+    //true if the user passed any reference FASTA or FASTA directory at all
+    pub fn has_references(&self) -> bool {
+        self.ref1.is_some() || self.ref2.is_some() || !self.refs.is_empty() || !self.out_refs.is_empty() || self.ref_dir.is_some()
+    }
+
+    //index of the input that receives reads unmapped everywhere, or None to discard them
+    pub fn unmapped_dest(&self, labels: &[String]) -> Result<Option<usize>, String> {
+        if self.unmapped == "discard" {
+            return Ok(None);
+        }
+        match label_index(&self.unmapped, labels) {
+            Some(i) => Ok(Some(i)),
+            None => Err(format!("--unmapped '{}' is not an input label, asm1..asm{}, or discard", self.unmapped, labels.len())),
+        }
+    }
+
+    //index of the input of --tie-prefer, None without it
+    pub fn tie_prefer_index(&self, labels: &[String]) -> Result<Option<usize>, String> {
+        match &self.tie_prefer {
+            None => Ok(None),
+            Some(dest) => label_index(dest, labels)
+                .map(Some)
+                .ok_or_else(|| format!("--tie-prefer '{}' is not an input label or asm1..asm{}", dest, labels.len())),
+        }
+    }
+}
+
+//index of an input given by its label, or as asmN for the Nth input regardless of its label
+fn label_index(dest: &str, labels: &[String]) -> Option<usize> {
+    if let Some(i) = labels.iter().position(|l| l == dest) {
+        return Some(i);
+    }
+    dest.strip_prefix("asm").and_then(|n| n.parse::<usize>().ok()).filter(|&n| n >= 1 && n <= labels.len()).map(|n| n - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_suffixes_and_overflow() {
+        assert_eq!(parse_size("512"), Ok(512));
+        assert_eq!(parse_size("2k"), Ok(2048));
+        assert_eq!(parse_size("768M"), Ok(768 << 20));
+        assert!(parse_size("99999999999G").is_err());
+        assert!(parse_size(&format!("{}K", usize::MAX)).is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("1.5G").is_err());
+    }
 }
@@ -0,0 +1,479 @@
+use std::cmp::max;
+use std::collections::HashMap;
+use std::iter::Peekable;
+
+use rust_htslib::bam::{self, record::Aux, record::Cigar, Read, Record};
+
+use crate::cli::{Cli, NameOrder, OutFormat, ScoreSource};
+use crate::bins::ReadBin;
+use crate::calibrate::Calibration;
+use crate::refs::RefResolver;
+use crate::het::HetSites;
+use crate::score::Scorer;
+use crate::sam::{self, RecordStream, SamWriter};
+use crate::ties::TieBreaker;
+
+//haplotype of every contig of a concatenated diploid (or polyploid) reference
+struct HapMap {
+    //haplotype names, in the order they are first listed
+    names: Vec<String>,
+    //haplotype index of every target id, None for contigs that belong to no haplotype
+    of_tid: Vec<Option<usize>>,
+    //same, by contig name, to resolve SA tag entries
+    of_name: HashMap<String, usize>,
+}
+
+//assign the contigs of the alignment header to haplotypes,
+//either from a contig<TAB>haplotype TSV (--hap-map) or by tags contained in contig names (--hap-tags)
+fn load_hap_map(args: &Cli, view: &bam::HeaderView) -> Result<HapMap, Box<dyn std::error::Error>> {
+    let contigs: Vec<String> = view.target_names().iter().map(|n| String::from_utf8_lossy(n).into_owned()).collect();
+    let mut names: Vec<String> = Vec::new();
+    let mut of_tid: Vec<Option<usize>> = vec![None; contigs.len()];
+
+    if let Some(path) = &args.hap_map {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read haplotype map '{}': {}", path, e))?;
+        let tids: HashMap<&str, usize> = contigs.iter().enumerate().map(|(i, c)| (c.as_str(), i)).collect();
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') { continue; }
+            let mut fields = line.split('\t');
+            let (contig, hap) = match (fields.next(), fields.next()) {
+                (Some(c), Some(h)) if !c.is_empty() && !h.is_empty() => (c, h),
+                _ => return Err(format!("{} line {}: expected a contig name and a haplotype label separated by a tab", path, line_no + 1).into()),
+            };
+            let hap = match names.iter().position(|h| h == hap) {
+                Some(h) => h,
+                None => { names.push(hap.to_string()); names.len() - 1 }
+            };
+            //contigs missing from the alignment header are fine, the map may cover a larger assembly
+            if let Some(&tid) = tids.get(contig) {
+                if matches!(of_tid[tid], Some(h) if h != hap) {
+                    return Err(format!("{} line {}: contig '{}' is assigned to more than one haplotype", path, line_no + 1, contig).into());
+                }
+                of_tid[tid] = Some(hap);
+            }
+        }
+        if names.len() < 2 {
+            return Err(format!("Haplotype map '{}' needs at least two haplotypes", path).into());
+        }
+    } else {
+        //same rule as separate_haps_fasta: the first tag found in the contig name wins
+        names = args.hap_tags.clone();
+        for (tid, contig) in contigs.iter().enumerate() {
+            of_tid[tid] = names.iter().position(|t| contig.contains(t.as_str()));
+        }
+    }
+
+    //a haplotype without contigs is almost certainly a typo in the map or tags
+    for (h, name) in names.iter().enumerate() {
+        if !of_tid.contains(&Some(h)) {
+            return Err(format!("No contig in the alignment header belongs to haplotype '{}'", name).into());
+        }
+    }
+    let unassigned = of_tid.iter().filter(|h| h.is_none()).count();
+    if unassigned > 0 {
+        eprintln!("Warning: {} contig(s) belong to no haplotype, alignments to them are ignored", unassigned);
+    }
+
+    let of_name = contigs.into_iter().zip(of_tid.iter())
+        .filter_map(|(c, h)| h.map(|h| (c, h)))
+        .collect();
+    Ok(HapMap { names, of_tid, of_name })
+}
+
+//header for one haplotype's output: the input header with only that haplotype's @SQ lines
+//returns the header and the new target id of every input target id (-1 if it is not in this haplotype)
+fn hap_header(view: &bam::HeaderView, hap: usize, hap_map: &HapMap) -> Result<(bam::Header, Vec<i32>), Box<dyn std::error::Error>> {
+    let text = String::from_utf8_lossy(view.as_bytes()).into_owned();
+    let mut out = String::new();
+    let mut tids: Vec<i32> = Vec::with_capacity(hap_map.of_tid.len());
+    let mut n_kept = 0;
+    for line in text.lines().filter(|l| !l.is_empty()) {
+        if line.starts_with("@SQ") {
+            if hap_map.of_tid.get(tids.len()) != Some(&Some(hap)) {
+                tids.push(-1);
+                continue;
+            }
+            tids.push(n_kept);
+            n_kept += 1;
+        }
+        out.push_str(line);
+        out.push('\n');
+    }
+    //make sure @SQ lines and the binary target list agree, otherwise target ids would be wrong
+    if tids.len() != view.target_count() as usize {
+        return Err(format!("Input header has {} @SQ lines but {} targets", tids.len(), view.target_count()).into());
+    }
+    let view = bam::HeaderView::from_bytes(out.as_bytes());
+    Ok((bam::Header::from_template(&view), tids))
+}
+
+//move a record's target ids into a haplotype's header, a mate on another haplotype becomes unplaced
+fn remap_tids(rec: &mut Record, tids: &[i32]) {
+    if rec.tid() >= 0 { rec.set_tid(tids[rec.tid() as usize]); }
+    if rec.mtid() >= 0 {
+        let mtid = tids[rec.mtid() as usize];
+        rec.set_mtid(mtid);
+        if mtid < 0 { rec.set_mpos(-1); }
+    }
+}
+
+//primary line of a read, i.e. neither secondary nor supplementary
+fn is_primary(rec: &Record) -> bool {
+    !rec.is_secondary() && !rec.is_supplementary()
+}
+
+//split the alignments of one read into one sub-cluster per haplotype, alignments to other contigs are dropped
+//a haplotype without the read's primary alignment is represented by its best secondary alignment,
+//which is promoted to primary so it is scored like a primary chain and given the read's SEQ/QUAL;
+//with paired reads this is done per mate
+fn split_by_hap(cluster: &[Record], hap_map: &HapMap, scorer: &Scorer, paired: bool, subs: &mut [Vec<Record>]) {
+    for sub in subs.iter_mut() {
+        sub.clear();
+    }
+    for rec in cluster.iter().filter(|r| r.tid() >= 0) {
+        if let Some(h) = hap_map.of_tid[rec.tid() as usize] {
+            subs[h].push(rec.clone());
+        }
+    }
+
+    let mates: &[u8] = if paired { &[0, 1, 2] } else { &[0] };
+    let of_mate = |r: &Record, m: u8| !paired || sam::mate(r) == m;
+    for sub in subs.iter_mut() {
+        for &m in mates {
+            if sub.iter().any(|r| of_mate(r, m) && is_primary(r)) {
+                continue;
+            }
+            let primary = cluster.iter().find(|r| of_mate(r, m) && is_primary(r));
+            //aligners list secondaries best first, reversing makes max_by keep the first of equal scores
+            //a secondary without a score ranks last
+            let best = sub.iter().enumerate().rev()
+                .filter(|(_, r)| of_mate(r, m) && r.is_secondary())
+                .map(|(k, r)| (k, secondary_score(r, primary, scorer)))
+                .max_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal))
+                .map(|(k, _)| k);
+            if let Some(k) = best {
+                let flags = sub[k].flags();
+                sub[k].set_flags(flags & !0x100);
+                if let Some(primary) = primary {
+                    fill_seq(&mut sub[k], primary);
+                }
+            }
+        }
+    }
+}
+
+//score of a secondary record, which is given the read's SEQ first if the score is computed from it (--score ref)
+fn secondary_score(rec: &Record, primary: Option<&Record>, scorer: &Scorer) -> Option<f64> {
+    match primary {
+        Some(primary) if scorer.needs_reference() && rec.seq_len() == 0 => {
+            let mut rec = rec.clone();
+            fill_seq(&mut rec, primary);
+            scorer.record(&rec).ok()
+        }
+        _ => scorer.record(rec).ok(),
+    }
+}
+
+//secondary records usually carry no SEQ/QUAL (minimap2 leaves them out),
+//so a promoted secondary takes them from the read's primary record, in its own orientation
+fn fill_seq(rec: &mut Record, primary: &Record) {
+    if rec.seq_len() > 0 || primary.seq_len() == 0 { return; }
+    //a hard clipped primary does not hold the whole read
+    if primary.cigar().iter().any(|c| matches!(c, Cigar::HardClip(_))) { return; }
+
+    let mut seq = primary.seq().as_bytes();
+    let mut qual = primary.qual().to_vec();
+    if primary.is_reverse() != rec.is_reverse() {
+        seq = sam::revcomp(&seq);
+        qual.reverse();
+    }
+    //leave out bases hard clipped from the promoted record
+    let cigar = rec.cigar().take();
+    let clip = |c: Option<&Cigar>| match c { Some(Cigar::HardClip(l)) => *l as usize, _ => 0 };
+    let (left, right) = (clip(cigar.0.first()), clip(cigar.0.last()));
+    let qlen: usize = cigar.0.iter().map(|c| match c {
+        Cigar::Match(l) | Cigar::Ins(l) | Cigar::SoftClip(l) | Cigar::Equal(l) | Cigar::Diff(l) => *l as usize,
+        _ => 0,
+    }).sum();
+    if left + qlen + right != seq.len() { return; }
+
+    let qname = rec.qname().to_vec();
+    rec.set(&qname, Some(&cigar), &seq[left..left + qlen], &qual[left..left + qlen]);
+}
+
+//keep only the SA tag entries on contigs of the given haplotype, dropping the tag if none are left
+fn filter_sa(rec: &mut Record, hap: usize, hap_map: &HapMap) -> Result<(), Box<dyn std::error::Error>> {
+    let sa = match rec.aux(b"SA") {
+        Ok(Aux::String(s)) => s.to_string(),
+        _ => return Ok(()),
+    };
+    let kept: String = sa.split_terminator(';')
+        .filter(|e| hap_map.of_name.get(e.split(',').next().unwrap_or("")) == Some(&hap))
+        .map(|e| format!("{};", e))
+        .collect();
+    if kept.len() != sa.len() {
+        rec.remove_aux(b"SA")?;
+        if !kept.is_empty() { rec.push_aux(b"SA", Aux::String(&kept))?; }
+    }
+    Ok(())
+}
+
+//output of one haplotype (or the merged output): alignments, or the reads themselves
+enum HapWriter {
+    Aln(SamWriter),
+    Reads(ReadBin),
+}
+
+impl HapWriter {
+    //header is the output's own (with only the haplotype's contigs), input is the input header whose @RG lines read bins keep
+    fn create(path: &str, header: &bam::Header, format: OutFormat, reference: Option<&str>, input: &bam::HeaderView, args: &Cli) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(match format {
+            OutFormat::Fastq => HapWriter::Reads(ReadBin::create_fastq(path, args.bgzf || path.to_ascii_lowercase().ends_with(".gz"), args)?),
+            OutFormat::Ubam => HapWriter::Reads(ReadBin::create_ubam(path, &[input], args)?),
+            _ => HapWriter::Aln(SamWriter::create(path, header, format.htslib().unwrap(), reference, args)?),
+        })
+    }
+
+    fn set_threads(&mut self, n_threads: usize) -> Result<(), rust_htslib::errors::Error> {
+        match self {
+            HapWriter::Aln(writer) => writer.set_threads(n_threads),
+            HapWriter::Reads(bin) => bin.set_threads(n_threads),
+        }
+    }
+
+    fn finish(self) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            HapWriter::Aln(writer) => writer.finish(),
+            HapWriter::Reads(bin) => bin.finish(),
+        }
+    }
+}
+
+//assign reads from a single alignment against the concatenated diploid reference
+//every read's alignments are split by haplotype and scored like the per-haplotype inputs of process_sam,
+//the winning haplotype's alignments are written, with its best alignment as primary
+pub fn process_diploid(args: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    let path = &args.inputs[0];
+    let mut reader = sam::open_reader(path)
+        .map_err(|e| format!("Failed to open input file '{}': {}", path, e))?;
+    let format = sam::get_format(&reader)
+        .map_err(|e| format!("Failed to identify input file format: {}", e))?;
+
+    let hap_map = load_hap_map(args, reader.header())?;
+    let labels = args.labels_for(&hap_map.names, "haplotypes")?;
+    let unmapped_dest = args.unmapped_dest(&labels)?;
+    let mut calibration = Calibration::new(args, &labels)?;
+    let mut ties = TieBreaker::new(args, &labels)?;
+    let n = labels.len();
+
+    //the concatenated diploid FASTA is the reference of the input, found from the @SQ M5/UR fields if not given
+    let mut resolver = RefResolver::new(args.ref_dir.as_deref(), &args.tmp_dir());
+    if let bam::Format::Cram = format {
+        if let Some(reference) = resolver.resolve(reader.header(), args.reference(0), path)? {
+            reader.set_reference(&reference)
+                .map_err(|e| format!("Failed to set reference for Reader: {}", e))?;
+        }
+    }
+
+    //--score ref reads the bases of the concatenated diploid FASTA
+    let mut scorer = Scorer::new(args);
+    if scorer.needs_reference() {
+        let fasta = resolver.fasta(reader.header(), args.reference(0), path)?;
+        scorer = scorer.with_reference(&fasta, reader.header())?;
+    }
+
+    //heterozygous sites on the contigs of both haplotypes, REF being the allele of the haplotype whose contig it is on
+    let hets = match args.het_vcf.first().filter(|v| !v.is_empty()) {
+        Some(_) if n != 2 => return Err(format!("--het-vcf compares two haplotypes, but {} were found", n).into()),
+        Some(vcf) => Some(HetSites::load(vcf, reader.header(), path)?),
+        None => None,
+    };
+
+    //outputs keep the input format unless another SAM/BAM/CRAM format (or FASTQ/unaligned BAM read bins) was asked for,
+    //with --out-format or by the extension of an explicit output path
+    let n_outputs = if args.merged.is_some() { 1 } else { n };
+    let mut out_formats = Vec::with_capacity(n_outputs);
+    for i in 0..n_outputs {
+        out_formats.push(match args.out_format.or_else(|| args.named_output(i).and_then(OutFormat::from_path)) {
+            Some(f) if f.is_reads() || f.htslib().is_some() => f,
+            Some(_) => return Err("Output format with --hap-map/--hap-tags must be sam, bam, cram, fastq or ubam".into()),
+            None => match format {
+                bam::Format::Sam => OutFormat::Sam,
+                bam::Format::Bam => OutFormat::Bam,
+                bam::Format::Cram => OutFormat::Cram,
+            },
+        });
+    }
+    let any_cram = out_formats.contains(&OutFormat::Cram);
+    if args.has_references() && args.score_source() != ScoreSource::Ref && !matches!(format, bam::Format::Cram) && !any_cram {
+        eprintln!("Warning: --refs/--ref1/--ref2/--out-refs/--ref-dir are ignored without CRAM input or output or --score ref");
+    }
+    if (args.cram_version.is_some() || args.embed_ref) && !any_cram {
+        eprintln!("Warning: --cram-version/--embed-ref are ignored without CRAM output");
+    }
+    //every CRAM output is written against the input's reference (or --out-refs), whose contigs each output header holds
+    //resolved once from the full header, so a missing contig is reported before any output is created
+    let out_reference = if any_cram {
+        resolver.resolve(reader.header(), args.out_reference(0), "CRAM output")?
+    } else {
+        None
+    };
+    if args.sort_output && out_formats.iter().any(|f| f.is_reads()) {
+        eprintln!("Warning: --sort-output does not apply to FASTQ/unaligned BAM output, reads are written in input order");
+    }
+
+    //one writer per haplotype holding only its contigs, named from --out-dir/--prefix and the label or given by --outputs,
+    //or a single writer with the full input header if the user asked for merged output
+    let mut writers = Vec::with_capacity(n);
+    let mut tid_maps: Vec<Vec<i32>> = Vec::with_capacity(n);
+    if let Some(out_path) = &args.merged {
+        let header = bam::Header::from_template(reader.header());
+        writers.push(HapWriter::create(out_path, &header, out_formats[0], out_reference.as_deref(), reader.header(), args)
+            .map_err(|e| format!("Failed to create output file '{}': {}", out_path, e))?);
+    } else {
+        let extensions: Vec<&str> = out_formats.iter().map(|f| f.extension(args.bgzf)).collect();
+        let out_paths = args.output_paths(&labels, &extensions)?;
+        for (h, out_path) in out_paths.iter().enumerate() {
+            let (header, tids) = hap_header(reader.header(), h, &hap_map)?;
+            writers.push(HapWriter::create(out_path, &header, out_formats[h], out_reference.as_deref(), reader.header(), args)
+                .map_err(|e| format!("Failed to create output file '{}': {}", out_path, e))?);
+            tid_maps.push(tids);
+        }
+    }
+
+    //set threads, at least one for the reader and each writer
+    let avail_threads = max(1 + writers.len(), args.threads);
+    //assign write:reader threads (ideally) 3:1
+    let r = max(1, avail_threads / 4);
+    let w = (avail_threads - r) / writers.len();
+    reader.set_threads(r)?;
+    for writer in writers.iter_mut() {
+        writer.set_threads(w)?;
+    }
+
+    let read_groups = sam::read_group_samples(reader.header());
+    //names the contigs of tied reads' windows with --tie-balance
+    let header_view = reader.header().clone();
+
+    //records of one read must be adjacent, so coordinate-sorted input is grouped by read name first
+    let sort_order = sam::hd_tag(reader.header(), "SO");
+    let mut records: Peekable<RecordStream> = if sort_order.as_deref() == Some("coordinate") {
+        eprintln!("Sorting input by read name (sort order: coordinate)");
+        let header = reader.header().clone();
+        let sorted = crate::sort::sort_records(reader.records(), &header, NameOrder::Natural.record_cmp(), args.sort_mem, &args.tmp_dir())
+            .map_err(|e| format!("Failed to group input by read name: {}", e))?;
+        (Box::new(sorted) as RecordStream).peekable()
+    } else {
+        (Box::new(reader.records()) as RecordStream).peekable()
+    };
+
+    let mut cluster: Vec<Record> = Vec::with_capacity(10);
+    let mut subs: Vec<Vec<Record>> = (0..n).map(|_| Vec::with_capacity(10)).collect();
+    let mut summary = crate::Summary::new(n);
+    summary.set_samples(&read_groups);
+    //reads aligned only to contigs outside every haplotype
+    let mut off_haplotype: u64 = 0;
+
+    loop {
+        sam::get_clusters(&mut records, &mut cluster)?;
+        if cluster.is_empty() { break; }
+        let qname = cluster[0].qname().to_vec();
+
+        split_by_hap(&cluster, &hap_map, &scorer, args.paired, &mut subs);
+        let unmapped = cluster.iter().all(|r| r.is_unmapped());
+        if !unmapped && subs.iter().all(|s| s.is_empty()) {
+            off_haplotype += 1;
+            continue;
+        }
+
+        //score every haplotype's sub-cluster like a separate input, haplotypes without alignments are not scored
+        let mut scores = Vec::with_capacity(n);
+        for sub in subs.iter() {
+            scores.push(if sub.is_empty() {
+                None
+            } else if args.paired {
+                sam::get_fragment_score(sub, &scorer, args.pair_bonus)?
+            } else {
+                Some(sam::get_weighted_score(sub, &scorer)?)
+            });
+        }
+        let (mut winner, mut hapq) = crate::pick_winner(&scores, args);
+        //with --hapq-table, HapQ of reads won by score is that of their bin
+        let mut features = calibration.features(&scores, &winner, args.match_sc, |h| sam::read_length(&subs[h]));
+        hapq = calibration.hapq(features, hapq, args.model.cap);
+        //with --het-vcf, reads covering heterozygous sites are assigned by them instead
+        let support = hets.as_ref().and_then(|hets| {
+            let evidence: Vec<Option<crate::SiteEvidence>> = subs.iter()
+                .map(|sub| if sub.is_empty() { None } else { Some(hets.evidence(sub)) })
+                .collect();
+            crate::site_support(&evidence)
+        });
+        if let Some((w, hq)) = support.as_ref().and_then(|s| crate::pick_by_sites(s, &scores, args)) {
+            (winner, hapq) = (w, hq);
+            summary.by_sites += 1;
+            features = None;
+        }
+        calibration.record(&qname, &winner, features);
+        //secondary alignments within the winning haplotype make its placement ambiguous (--hapq-multimap secondary)
+        if let (crate::Winner::Best(h), Some(hq), false) = (&winner, hapq, args.hapq_multimap.is_empty()) {
+            if let Some(cap) = sam::placement_cap(&subs[*h], &scorer, args) {
+                hapq = Some(hq.min(cap));
+            }
+        }
+        summary.count(&winner, hapq, sam::read_group(&cluster).as_deref());
+
+        let stats = |h: usize| sam::tie_stats(&subs[h], scores[h].map_or(0, |s| s.1), &header_view);
+        //counted once, also if a tie is written to several bins
+        let mut unbinned = false;
+        for h in crate::destinations(&winner, &qname, &mut ties, stats, unmapped_dest) {
+            let out = if tid_maps.is_empty() { 0 } else { h };
+            //merged output keeps the haplotype in the hl tag
+            let label = if tid_maps.is_empty() && !unmapped { Some(labels[h].as_str()) } else { None };
+            //read bins take the read from its primary records, wherever they are aligned
+            let writer = match &mut writers[out] {
+                HapWriter::Reads(bin) => {
+                    if !bin.write_read(&cluster, hapq, label)? {
+                        unbinned = true;
+                    }
+                    continue;
+                }
+                HapWriter::Aln(writer) => writer,
+            };
+            //an unmapped read is written as it came in
+            let recs = if unmapped { &mut cluster } else { &mut subs[h] };
+            //chain of the haplotype's split alignments, with promoted secondaries (see sam::chains)
+            let chains = if args.chain && !unmapped { sam::chains(recs) } else { [None, None, None] };
+            //sites supporting and conflicting with the haplotype written to
+            let sites = support.filter(|_| !unmapped).map(|(counts, _)| (counts[h], counts[1 - h]));
+            for rec in recs.iter_mut() {
+                if !unmapped { filter_sa(rec, h, &hap_map)?; }
+                if let Some(hq) = hapq { rec.push_aux(b"hq", Aux::U8(hq))?; }
+                if let Some(label) = label { rec.push_aux(b"hl", Aux::String(label))?; }
+                if let Some(hc) = &chains[sam::mate(rec) as usize] { rec.push_aux(b"hc", Aux::String(hc))?; }
+                if let Some((hs, hx)) = sites {
+                    rec.push_aux(b"hs", Aux::U32(hs))?;
+                    rec.push_aux(b"hx", Aux::U32(hx))?;
+                }
+                if let Some(tids) = tid_maps.get(h) { remap_tids(rec, tids); }
+                writer.write(rec)?;
+            }
+        }
+        if unbinned {
+            summary.count_unbinned(&qname);
+        }
+    }
+    for writer in writers {
+        writer.finish()?;
+    }
+    summary.print(&labels);
+    if let Some(path) = &args.summary_tsv {
+        summary.write_tsv(path, &labels).map_err(|e| format!("Failed to write summary '{}': {}", path, e))?;
+    }
+    if off_haplotype > 0 {
+        eprintln!("Reads aligned only to contigs in no haplotype (not written): {}", off_haplotype);
+    }
+    calibration.finish(args)?;
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use rust_htslib::bam::{HeaderView, Record};
+
+use crate::open_text;
+use crate::SiteEvidence;
+
+//base quality given to the evidence of reads without base qualities
+const MISSING_QUAL: u8 = 20;
+
+//heterozygous differences between the two haplotypes (--het-vcf) in the coordinates of one input:
+//REF is the allele of the haplotype whose contig the site is on, the first ALT the allele of the other haplotype
+pub(crate) struct HetSites {
+    //sites of every target id of the input header, sorted by position
+    by_tid: Vec<Vec<Site>>,
+}
+
+struct Site {
+    //0-based position of the first REF base
+    pos: i64,
+    ref_allele: Vec<u8>,
+    alt: Vec<u8>,
+}
+
+//a CIGAR operation placed on the reference and the read
+struct Block {
+    op: char,
+    rstart: i64,
+    qstart: usize,
+    len: usize,
+}
+
+impl Block {
+    fn rend(&self) -> i64 {
+        self.rstart + self.len as i64
+    }
+}
+
+impl HetSites {
+    //read the sites of a VCF (gzip/BGZF is decompressed) on the contigs of a header, what names the input in messages
+    //symbolic, missing and complex alleles (neither SNV/MNP nor an indel sharing its first bases) are skipped
+    pub(crate) fn load(path: &str, header: &HeaderView, what: &str) -> Result<Self, String> {
+        let tids: HashMap<&[u8], usize> = header.target_names().into_iter().enumerate().map(|(i, n)| (n, i)).collect();
+        let mut by_tid: Vec<Vec<Site>> = (0..tids.len()).map(|_| Vec::new()).collect();
+        let reader = open_text(path).map_err(|e| format!("Failed to open VCF '{}': {}", path, e))?;
+        let (mut other_contigs, mut skipped) = (0u64, 0u64);
+        for (n, line) in reader.lines().enumerate() {
+            let line = line.map_err(|e| format!("Failed to read VCF '{}': {}", path, e))?;
+            if line.starts_with('#') || line.is_empty() { continue; }
+            let fields: Vec<&str> = line.splitn(6, '\t').collect();
+            if fields.len() < 5 {
+                return Err(format!("Malformed VCF line {} of '{}': fewer than 5 columns", n + 1, path));
+            }
+            let Some(&tid) = tids.get(fields[0].as_bytes()) else {
+                other_contigs += 1;
+                continue;
+            };
+            let pos: i64 = fields[1].parse()
+                .map_err(|e| format!("Invalid POS '{}' on VCF line {} of '{}': {}", fields[1], n + 1, path, e))?;
+            let ref_allele = fields[3].to_ascii_uppercase().into_bytes();
+            let alt = fields[4].split(',').next().unwrap_or(".").to_ascii_uppercase().into_bytes();
+            let bases = |a: &[u8]| !a.is_empty() && a.iter().all(|b| b"ACGTN".contains(b));
+            let simple = ref_allele.len() == alt.len() || ref_allele.starts_with(&alt) || alt.starts_with(&ref_allele);
+            if !bases(&ref_allele) || !bases(&alt) || ref_allele == alt || !simple || pos < 1 {
+                skipped += 1;
+                continue;
+            }
+            by_tid[tid].push(Site { pos: pos - 1, ref_allele, alt });
+        }
+        for sites in by_tid.iter_mut() {
+            sites.sort_by_key(|s| s.pos);
+        }
+        if other_contigs > 0 {
+            eprintln!("Warning: {} site(s) of VCF '{}' are on contigs not in {}, they are ignored", other_contigs, path, what);
+        }
+        if skipped > 0 {
+            eprintln!("Warning: {} site(s) of VCF '{}' have symbolic, missing or complex alleles, they are ignored", skipped, path);
+        }
+        Ok(HetSites { by_tid })
+    }
+
+    //evidence of the non-secondary alignments of a read at the sites they cover
+    pub(crate) fn evidence(&self, cluster: &[Record]) -> SiteEvidence {
+        let mut ev = SiteEvidence::default();
+        for rec in cluster.iter().filter(|r| !r.is_secondary() && !r.is_unmapped()) {
+            self.add_record(rec, &mut ev);
+        }
+        ev
+    }
+
+    fn add_record(&self, rec: &Record, ev: &mut SiteEvidence) {
+        let sites = match self.by_tid.get(rec.tid() as usize) {
+            Some(sites) if !sites.is_empty() => sites,
+            _ => return,
+        };
+        let (start, end) = (rec.pos(), rec.cigar().end_pos());
+        let first = sites.partition_point(|s| s.pos < start);
+        let covered = &sites[first..sites.partition_point(|s| s.pos < end)];
+        if covered.is_empty() || rec.seq_len() == 0 { return; }
+
+        let mut blocks = Vec::new();
+        let (mut r, mut q) = (start, 0usize);
+        for c in rec.cigar().iter() {
+            let (op, len) = (c.char(), c.len() as usize);
+            blocks.push(Block { op, rstart: r, qstart: q, len });
+            if matches!(op, 'M' | '=' | 'X' | 'D' | 'N') { r += len as i64; }
+            if matches!(op, 'M' | '=' | 'X' | 'I' | 'S') { q += len; }
+        }
+        let seq = rec.seq().as_bytes();
+        let qual = rec.qual();
+        let base_qual = |q: usize| match qual.get(q) {
+            Some(255) | None => MISSING_QUAL,
+            Some(&bq) => bq,
+        };
+        for site in covered {
+            match read_allele(site, &blocks, &seq) {
+                Some((true, q)) => { ev.own += 1; ev.own_qual += base_qual(q) as u32; }
+                Some((false, q)) => { ev.other += 1; ev.other_qual += base_qual(q) as u32; }
+                None => {}
+            }
+        }
+    }
+}
+
+//allele of a read at a site: true for REF, false for ALT, with the read position whose base quality weighs the evidence
+//(the lowest of the compared bases for SNVs/MNPs, the anchor base for indels); None if the read shows neither
+fn read_allele(site: &Site, blocks: &[Block], seq: &[u8]) -> Option<(bool, usize)> {
+    let aligned = |b: &Block| matches!(b.op, 'M' | '=' | 'X');
+    //aligned block holding a reference position
+    let at = |pos: i64| blocks.iter().position(|b| aligned(b) && b.rstart <= pos && pos < b.rend());
+    let (rlen, alen) = (site.ref_allele.len(), site.alt.len());
+
+    if rlen == alen {
+        let k = at(site.pos)?;
+        let b = &blocks[k];
+        if site.pos + rlen as i64 > b.rend() { return None; }
+        let q = b.qstart + (site.pos - b.rstart) as usize;
+        let bases = seq.get(q..q + rlen)?;
+        return if bases == site.ref_allele.as_slice() {
+            Some((true, q))
+        } else if bases == site.alt.as_slice() {
+            Some((false, q))
+        } else {
+            None
+        };
+    }
+
+    //indel: the shorter allele is the anchor the longer one starts with
+    let anchor = rlen.min(alen);
+    let anchor_end = site.pos + anchor as i64 - 1;
+    let k = at(anchor_end)?;
+    let b = &blocks[k];
+    let q = b.qstart + (anchor_end - b.rstart) as usize;
+    if anchor_end + 1 < b.rend() {
+        //aligned straight across the anchor: REF, if every REF base is aligned (the bases ALT deletes, if any)
+        return (site.pos + rlen as i64 <= b.rend()).then_some((true, q));
+    }
+    //the block ends at the anchor: the gap that follows decides
+    let next = blocks[k + 1..].iter().find(|b| b.op != 'P')?;
+    let d = rlen.abs_diff(alen);
+    match next.op {
+        'D' if rlen > alen && next.len == d => Some((false, q)),
+        'I' if alen > rlen && next.len == d && seq.get(next.qstart..next.qstart + d) == Some(&site.alt[anchor..]) => Some((false, q)),
+        //a gap of another length or sequence, or a clip
+        _ => None,
+    }
+}
@@ -0,0 +1,765 @@
+use std::cmp::max;
+use std::io::{BufRead, Lines, Read as IoRead, Write};
+use std::os::fd::AsRawFd;
+use std::iter::Peekable;
+
+use flate2::read::MultiGzDecoder;
+use rust_htslib::bam::{self, record::Aux, Read, Record};
+
+use crate::bins::ReadBin;
+use crate::calibrate::Calibration;
+use crate::cli::{Cli, InputFormat, NameOrder, OutFormat, ScoreSource};
+use crate::het::HetSites;
+use crate::paf;
+use crate::{open_text, text_reader};
+use crate::refs::RefResolver;
+use crate::score::Scorer;
+use crate::sam::{self, MergedTargets, OwnedRecords, RecordStream, SamWriter};
+use crate::sort::LineSorter;
+use crate::ties::{TieBreaker, TieStats};
+
+//one input being read one read at a time, with the alignments of its current read
+//SAM/BAM/CRAM and PAF/GAF inputs can be mixed, every input is scored the same way
+enum Input {
+    //SAM/BAM/CRAM read through htslib, the header names the targets when writing PAF
+    //replay is the thread feeding a piped input to htslib (see Peeked::open_sam)
+    Sam { header: bam::HeaderView, records: Peekable<RecordStream<'static>>, cluster: Vec<Record>, replay: Option<Replay> },
+    //PAF/GAF lines
+    Paf { lines: Peekable<Lines<Box<dyn BufRead>>>, gaf: bool, cluster: Vec<String> },
+}
+
+impl Input {
+    //move on to the next read, the cluster is left empty at the end of the input
+    fn next_cluster(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Input::Sam { records, cluster, replay, .. } => {
+                sam::get_clusters(records, cluster)?;
+                //htslib sees the end of a replayed pipe also when reading it failed, so check how the replay ended
+                if cluster.is_empty() {
+                    if let Some(replay) = replay.take() {
+                        replay.join().map_err(|_| "Input replay thread panicked".to_string())??;
+                    }
+                }
+                Ok(())
+            }
+            Input::Paf { lines, cluster, .. } => Ok(paf::get_clusters(lines, cluster)?),
+        }
+    }
+
+    //read group of the current read, if its records carry an RG tag
+    fn read_group(&self) -> Option<String> {
+        match self {
+            Input::Sam { cluster, .. } => sam::read_group(cluster),
+            Input::Paf { cluster, .. } => paf::read_group(cluster),
+        }
+    }
+
+    //read name of the current read, None at the end of the input
+    fn qname(&self) -> Option<&[u8]> {
+        match self {
+            Input::Sam { cluster, .. } => cluster.first().map(|r| r.qname()),
+            Input::Paf { cluster, .. } => cluster.first().map(|l| paf::line_qname(l).as_bytes()),
+        }
+    }
+
+    //weighted score and number of non-secondary alignments of the current read, None if it is unmapped
+    fn score(&self, scorer: &Scorer, args: &Cli) -> Result<Option<(f32, u32)>, Box<dyn std::error::Error>> {
+        match self {
+            Input::Sam { cluster, .. } => {
+                //both mates of a fragment are in the cluster, either one can be the unmapped one
+                if args.paired { return sam::get_fragment_score(cluster, scorer, args.pair_bonus); }
+                if cluster[0].is_unmapped() { return Ok(None); }
+                Ok(Some(sam::get_weighted_score(cluster, scorer)?))
+            }
+            Input::Paf { cluster, gaf, .. } => {
+                if paf::is_unmapped(cluster) { return Ok(None); }
+                Ok(Some(paf::get_weighted_score(cluster, scorer, *gaf)?))
+            }
+        }
+    }
+
+    //HapQ cap from how uniquely the current read is placed in this input, None without --hapq-multimap or any signal
+    fn placement_cap(&self, scorer: &Scorer, args: &Cli) -> Option<u8> {
+        if args.hapq_multimap.is_empty() { return None; }
+        match self {
+            Input::Sam { cluster, .. } => sam::placement_cap(cluster, scorer, args),
+            Input::Paf { cluster, .. } => paf::placement_cap(cluster, scorer, args),
+        }
+    }
+
+    //length of the current read, for HapQ calibration
+    fn read_length(&self) -> u32 {
+        match self {
+            Input::Sam { cluster, .. } => sam::read_length(cluster),
+            Input::Paf { cluster, .. } => paf::read_length(cluster),
+        }
+    }
+
+    //stats of the current read that break ties in score, splits being those of its score
+    fn tie_stats(&self, splits: u32) -> TieStats {
+        match self {
+            Input::Sam { header, cluster, .. } => sam::tie_stats(cluster, splits, header),
+            Input::Paf { cluster, .. } => paf::tie_stats(cluster, splits),
+        }
+    }
+
+    //evidence of the current read at the heterozygous sites of this input, None if it is unmapped here
+    fn site_evidence(&self, sites: &HetSites) -> Option<crate::SiteEvidence> {
+        match self {
+            Input::Sam { cluster, .. } if cluster.iter().any(|r| !r.is_unmapped()) => Some(sites.evidence(cluster)),
+            _ => None,
+        }
+    }
+}
+
+//one output file or stream
+enum Output {
+    //SAM/BAM/CRAM, with the mapping of every input's targets if the header holds every input's contigs
+    Sam { writer: SamWriter, merged: Option<MergedTargets> },
+    //PAF/GAF lines, BGZF-compressed with --bgzf or a .gz path, collected in a sort with --sort-output
+    Paf { writer: Box<dyn Write>, sorter: Option<LineSorter> },
+    //reads binned by haplotype, FASTQ or unaligned BAM
+    Reads(ReadBin),
+}
+
+impl Output {
+    //write one PAF/GAF line
+    fn write_line(&mut self, line: String) -> std::io::Result<()> {
+        match self {
+            Output::Paf { sorter: Some(sorter), .. } => sorter.push(line),
+            Output::Paf { writer, .. } => writeln!(writer, "{}", line),
+            Output::Sam { .. } | Output::Reads(_) => unreachable!("PAF/GAF lines go to PAF/GAF output"),
+        }
+    }
+
+    //write out sorted output and close it, reporting errors that dropping would hide (e.g. closed pipe)
+    fn finish(self) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Output::Sam { writer, .. } => writer.finish(),
+            Output::Paf { mut writer, sorter } => {
+                if let Some(sorter) = sorter {
+                    sorter.finish(&mut writer)?;
+                }
+                Ok(writer.flush()?)
+            }
+            Output::Reads(bin) => bin.finish(),
+        }
+    }
+}
+
+impl OutFormat {
+    //htslib format to write alignments in, None for PAF/GAF and read bins
+    pub(crate) fn htslib(self) -> Option<bam::Format> {
+        match self {
+            OutFormat::Sam => Some(bam::Format::Sam),
+            OutFormat::Bam => Some(bam::Format::Bam),
+            OutFormat::Cram => Some(bam::Format::Cram),
+            OutFormat::Paf | OutFormat::Gaf | OutFormat::Fastq | OutFormat::Ubam => None,
+        }
+    }
+
+    //true for outputs holding reads rather than alignments
+    pub(crate) fn is_reads(self) -> bool {
+        matches!(self, OutFormat::Fastq | OutFormat::Ubam)
+    }
+
+    //file extension of outputs in this format
+    pub(crate) fn extension(self, bgzf: bool) -> &'static str {
+        match (self, bgzf) {
+            (OutFormat::Sam, _) => ".sam",
+            (OutFormat::Bam, _) => ".bam",
+            (OutFormat::Cram, _) => ".cram",
+            (OutFormat::Paf, false) => ".paf",
+            (OutFormat::Paf, true) => ".paf.gz",
+            (OutFormat::Gaf, false) => ".gaf",
+            (OutFormat::Gaf, true) => ".gaf.gz",
+            (OutFormat::Fastq, false) => ".fastq",
+            (OutFormat::Fastq, true) => ".fastq.gz",
+            (OutFormat::Ubam, _) => ".unaligned.bam",
+        }
+    }
+
+    //format named by the extension of an output path, None if it names none (e.g. '-')
+    //.fq is FASTQ too, and .unaligned.bam is checked before .bam
+    pub(crate) fn from_path(path: &str) -> Option<OutFormat> {
+        let lower = path.to_ascii_lowercase();
+        if lower.ends_with(".fq") || lower.ends_with(".fq.gz") {
+            return Some(OutFormat::Fastq);
+        }
+        [OutFormat::Ubam, OutFormat::Sam, OutFormat::Bam, OutFormat::Cram, OutFormat::Paf, OutFormat::Gaf, OutFormat::Fastq].into_iter()
+            .find(|f| lower.ends_with(f.extension(false)) || lower.ends_with(f.extension(true)))
+    }
+}
+
+//PAF/GAF/FASTQ output is compressed with --bgzf, or if its path ends in .gz
+fn compress_output(path: &str, args: &Cli) -> bool {
+    args.bgzf || path.to_ascii_lowercase().ends_with(".gz")
+}
+
+//output format that keeps an input's own format
+fn own_format(format: InputFormat, htslib_format: Option<bam::Format>) -> OutFormat {
+    match (format, htslib_format) {
+        (InputFormat::Paf, _) => OutFormat::Paf,
+        (InputFormat::Gaf, _) => OutFormat::Gaf,
+        (_, Some(bam::Format::Bam)) => OutFormat::Bam,
+        (_, Some(bam::Format::Cram)) => OutFormat::Cram,
+        _ => OutFormat::Sam,
+    }
+}
+
+//resolve the format of every input: --paf/--gaf for all inputs, else --formats, detecting auto ones
+//detecting the format of a pipe or stdin reads its first bytes, which are returned to be read again
+fn input_formats(args: &Cli, paths: &[String]) -> Result<Vec<(InputFormat, Option<Peeked>)>, String> {
+    if !args.formats.is_empty() && args.formats.len() != paths.len() {
+        return Err(format!("--formats has {} entries but {} input files were given", args.formats.len(), paths.len()));
+    }
+    paths.iter().enumerate().map(|(i, path)| {
+        let format = if args.paf {
+            InputFormat::Paf
+        } else if args.gaf {
+            InputFormat::Gaf
+        } else {
+            args.formats.get(i).copied().unwrap_or(InputFormat::Auto)
+        };
+        if format == InputFormat::Auto { detect_format(path) } else { Ok((format, None)) }
+    }).collect()
+}
+
+//most bytes read from an input to detect its format
+const SNIFF_LIMIT: usize = 1 << 20;
+
+//thread copying a piped input into the pipe htslib reads, with the error reading the input if any
+type Replay = std::thread::JoinHandle<Result<u64, String>>;
+
+//the first bytes of a pipe or stdin, read to detect its format, and the rest of the stream
+struct Peeked {
+    head: Vec<u8>,
+    rest: Box<dyn IoRead + Send>,
+}
+
+impl Peeked {
+    //the whole stream again
+    fn into_stream(self) -> Box<dyn IoRead + Send> {
+        Box::new(std::io::Cursor::new(self.head).chain(self.rest))
+    }
+
+    //htslib opens inputs by path, so the stream is replayed into a new pipe reachable through the process' fd directory
+    //the /dev/fd directory exists on Linux, macOS and the BSDs; elsewhere the input needs --formats sam, which htslib opens directly
+    //the replay is joined once htslib reaches the end of the pipe, so a failed read of the input fails the run
+    fn open_sam(self, path: &str) -> Result<(bam::Reader, Replay), Box<dyn std::error::Error>> {
+        if !std::path::Path::new("/dev/fd").is_dir() {
+            return Err("SAM/BAM/CRAM from a pipe can only be detected on systems with /dev/fd, give its format with --formats sam".into());
+        }
+        let (pipe, mut writer) = std::io::pipe()?;
+        let mut stream = self.into_stream();
+        let path = path.to_string();
+        let replay = std::thread::spawn(move || {
+            std::io::copy(&mut stream, &mut writer).map_err(|e| format!("Failed to read input '{}': {}", path, e))
+        });
+        let reader = bam::Reader::from_path(format!("/dev/fd/{}", pipe.as_raw_fd()))?;
+        //htslib holds its own descriptor of the pipe
+        drop(pipe);
+        Ok((reader, replay))
+    }
+}
+
+//guess the format of an input from its name, else from its first line
+//pipes and stdin cannot be read twice, so their first bytes are kept (see Peeked)
+fn detect_format(path: &str) -> Result<(InputFormat, Option<Peeked>), String> {
+    let lower = path.to_ascii_lowercase();
+    let name = lower.strip_suffix(".gz").unwrap_or(&lower);
+    if name.ends_with(".paf") { return Ok((InputFormat::Paf, None)); }
+    if name.ends_with(".gaf") { return Ok((InputFormat::Gaf, None)); }
+    let mut stream: Box<dyn IoRead + Send> = if path == "-" {
+        Box::new(std::io::stdin())
+    } else {
+        match std::fs::File::open(path) {
+            Ok(file) => Box::new(file),
+            //htslib reports files it cannot open
+            Err(_) => return Ok((InputFormat::Sam, None)),
+        }
+    };
+    let regular = path != "-" && std::fs::metadata(path).map(|m| m.is_file()).unwrap_or(false);
+    let mut head = Vec::new();
+    let mut buf = [0u8; 65536];
+    let format = loop {
+        let n = match stream.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(format!("Failed to read input '{}': {}", path, e)),
+        };
+        head.extend_from_slice(&buf[..n]);
+        let complete = n == 0 || head.len() >= SNIFF_LIMIT;
+        if let Some(format) = sniff_format(&head, complete) {
+            break format;
+        }
+    };
+    let peeked = if regular { None } else { Some(Peeked { head, rest: stream }) };
+    Ok((format, peeked))
+}
+
+//format of an input from its first bytes, None if more are needed to tell
+//complete is set when there are no more bytes to read
+fn sniff_format(head: &[u8], complete: bool) -> Option<InputFormat> {
+    if head.starts_with(&[0x1f, 0x8b]) {
+        //decompress what has been read so far; a truncated gzip member gives the bytes before its end
+        let mut text = Vec::new();
+        let mut decoder = MultiGzDecoder::new(head);
+        let mut buf = [0u8; 8192];
+        while let Ok(n) = decoder.read(&mut buf) {
+            if n == 0 || text.len() >= SNIFF_LIMIT { break; }
+            text.extend_from_slice(&buf[..n]);
+        }
+        return sniff_text(&text, complete);
+    }
+    sniff_text(head, complete)
+}
+
+fn sniff_text(head: &[u8], complete: bool) -> Option<InputFormat> {
+    if head.starts_with(b"BAM\x01") || head.starts_with(b"CRAM") {
+        return Some(InputFormat::Sam);
+    }
+    let line = match head.iter().position(|&b| b == b'\n') {
+        Some(end) => &head[..end],
+        None if complete => head,
+        None => return None,
+    };
+    //PAF/GAF lines have at least 12 columns with the strand in the 5th, unlike SAM lines
+    let fields: Vec<&[u8]> = line.split(|&b| b == b'\t').collect();
+    if fields.len() < 12 || !matches!(fields[4], b"+" | b"-" | b"*") {
+        return Some(InputFormat::Sam);
+    }
+    //GAF paths of oriented segments; GAF with stable names looks like PAF, use a .gaf name or --formats
+    Some(if fields[5].starts_with(b">") || fields[5].starts_with(b"<") { InputFormat::Gaf } else { InputFormat::Paf })
+}
+
+//an input that has been opened, before it is turned into a read-by-read Input
+enum Opened {
+    Sam(bam::Reader, bam::Format),
+    Paf(Box<dyn BufRead>, bool),
+}
+
+//write one input's alignments of the current read to an output, converting between formats if needed
+//src is the index of the input, to move its targets into a merged header
+//label is the input label written as hl tag into merged output, so records still tell which input they came from
+//with chain, split alignments are tagged with their chain (hc tag)
+//sites are the heterozygous sites supporting and conflicting with the input written to (hs/hx tags), with --het-vcf
+//false if the read could not be written to a read bin, see ReadBin::write_read
+fn write_cluster(out: &mut Output, input: &mut Input, src: usize, hapq: Option<u8>, label: Option<&str>, chain: bool, sites: Option<(u32, u32)>) -> Result<bool, Box<dyn std::error::Error>> {
+    //helper: format hq and hl tag suffix if hapq/label are present
+    let mut tag_suffix = match hapq {
+        Some(hq) => format!("\thq:i:{}", hq),
+        None => String::new(),
+    };
+    if let Some(label) = label {
+        tag_suffix.push_str(&format!("\thl:Z:{}", label));
+    }
+    if let Some((hs, hx)) = sites {
+        tag_suffix.push_str(&format!("\ths:i:{}\thx:i:{}", hs, hx));
+    }
+    //chain of every mate of a SAM/BAM/CRAM read, see sam::chains
+    let sam_chains = match input {
+        Input::Sam { cluster, .. } if chain => sam::chains(cluster),
+        _ => [None, None, None],
+    };
+    match (out, input) {
+        (Output::Sam { writer, merged }, Input::Sam { cluster, .. }) => {
+            for rec in cluster.iter_mut() {
+                if let Some(hq) = hapq { rec.push_aux(b"hq", Aux::U8(hq))?; }
+                if let Some(label) = label { rec.push_aux(b"hl", Aux::String(label))?; }
+                if let Some(hc) = &sam_chains[sam::mate(rec) as usize] { rec.push_aux(b"hc", Aux::String(hc))?; }
+                if let Some((hs, hx)) = sites {
+                    rec.push_aux(b"hs", Aux::U32(hs))?;
+                    rec.push_aux(b"hx", Aux::U32(hx))?;
+                }
+                if let Some(merged) = merged { merged.apply(rec, src)?; }
+                writer.write(rec)?;
+            }
+        }
+        (out @ Output::Paf { .. }, Input::Paf { cluster, .. }) => {
+            if chain && !paf::is_unmapped(cluster) {
+                if let Some(hc) = paf::chain(cluster)? {
+                    tag_suffix.push_str(&format!("\thc:Z:{}", hc));
+                }
+            }
+            for line in cluster.iter() {
+                out.write_line(format!("{}{}", line, tag_suffix))?;
+            }
+        }
+        (out @ Output::Paf { .. }, Input::Sam { header, cluster, .. }) => {
+            for rec in cluster.iter() {
+                let hc = match &sam_chains[sam::mate(rec) as usize] {
+                    Some(hc) => format!("\thc:Z:{}", hc),
+                    None => String::new(),
+                };
+                out.write_line(format!("{}{}{}", sam::to_paf_line(rec, header), tag_suffix, hc))?;
+            }
+        }
+        (Output::Reads(bin), Input::Sam { cluster, .. }) => return bin.write_read(cluster, hapq, label),
+        (Output::Reads(_), Input::Paf { .. }) => unreachable!("PAF/GAF input cannot be binned, checked before"),
+        //PAF has no CIGAR or sequence, so only reads unmapped there can be written as SAM/BAM/CRAM
+        (Output::Sam { writer, .. }, Input::Paf { cluster, .. }) => {
+            let qname = paf::line_qname(&cluster[0]);
+            if !paf::is_unmapped(cluster) {
+                return Err(format!("Read '{}' is aligned in PAF/GAF input, which cannot be written as SAM/BAM/CRAM", qname).into());
+            }
+            let mut rec = sam::unmapped_record(qname.as_bytes());
+            if let Some(label) = label { rec.push_aux(b"hl", Aux::String(label))?; }
+            writer.write(&rec)?;
+        }
+    }
+    Ok(true)
+}
+
+//assign every read to the input it aligns best to, reading the inputs from the given paths
+//(args.inputs, or aligner output pipes with --reads); inputs may be in different formats
+pub fn process_inputs(args: &Cli, paths: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+
+    let labels = args.input_labels()?;
+    let unmapped_dest = args.unmapped_dest(&labels)?;
+    let mut calibration = Calibration::new(args, &labels)?;
+    let mut ties = TieBreaker::new(args, &labels)?;
+    let n = paths.len();
+    let (formats, mut peeked): (Vec<InputFormat>, Vec<Option<Peeked>>) = input_formats(args, paths)?.into_iter().unzip();
+    if args.paired {
+        if let Some(i) = formats.iter().position(|f| matches!(f, InputFormat::Paf | InputFormat::Gaf)) {
+            return Err(format!("--paired needs SAM/BAM/CRAM input, but {} is {:?}, which has no mate flags", labels[i], formats[i]).into());
+        }
+    }
+
+    //set threads
+    //if user specifies less than 2 per input, raise it (1 thread for each reader and each writer is needed)
+    let avail_threads = max(2 * n, args.threads);
+    //assign write:reader threads (ideally) 3:1
+    let r = max(1, avail_threads / (4 * n));
+
+    // read in all files, '-' reads from stdin, gzip/BGZF PAF input is decompressed
+    let mut resolver = RefResolver::new(args.ref_dir.as_deref(), &args.tmp_dir());
+    let mut opened = Vec::with_capacity(n);
+    //every input is scored against its own reference with --score ref
+    let mut scorers = Vec::with_capacity(n);
+    //heterozygous sites in the coordinates of every input, with --het-vcf
+    let mut hets: Vec<Option<HetSites>> = Vec::with_capacity(n);
+    //threads replaying piped SAM/BAM/CRAM inputs to htslib
+    let mut replays: Vec<Option<Replay>> = (0..n).map(|_| None).collect();
+    for (i, (path, label)) in paths.iter().zip(labels.iter()).enumerate() {
+        let scorer = Scorer::new(args);
+        let vcf = args.het_vcf.get(i).filter(|v| !v.is_empty());
+        match formats[i] {
+            InputFormat::Paf | InputFormat::Gaf => {
+                if scorer.needs_reference() {
+                    return Err(format!("{} is {:?} input, which holds no read sequences to rescore with --score ref", label, formats[i]).into());
+                }
+                if vcf.is_some() {
+                    return Err(format!("{} is {:?} input, which holds no read sequences to check at the sites of --het-vcf", label, formats[i]).into());
+                }
+                hets.push(None);
+                let reader = match peeked[i].take() {
+                    Some(peeked) => text_reader(peeked.into_stream()),
+                    None => open_text(path),
+                }.map_err(|e| format!("Failed to open {} file '{}': {}", label, path, e))?;
+                opened.push(Opened::Paf(reader, formats[i] == InputFormat::Gaf));
+                scorers.push(scorer);
+            }
+            _ => {
+                let (mut reader, replay) = match peeked[i].take() {
+                    Some(peeked) => peeked.open_sam(path).map(|(reader, replay)| (reader, Some(replay))),
+                    None => sam::open_reader(path).map(|reader| (reader, None)).map_err(|e| e.into()),
+                }.map_err(|e| format!("Failed to open {} file '{}': {}", label, path, e))?;
+                replays[i] = replay;
+                //detect format of the input file (i.e sam/cram/bam)
+                let format = sam::get_format(&reader)
+                    .map_err(|e| format!("Failed to identify {} file format: {}", label, e))?;
+                //a cram file needs the reference fasta it was written against,
+                //unless htslib finds every contig from the @SQ M5/UR fields itself
+                if let bam::Format::Cram = format {
+                    if let Some(reference) = resolver.resolve(reader.header(), args.reference(i), label)? {
+                        reader.set_reference(&reference)
+                            .map_err(|e| format!("Failed to set reference for {} Reader: {}", label, e))?;
+                    }
+                }
+                if scorer.needs_reference() {
+                    let fasta = resolver.fasta(reader.header(), args.reference(i), label)?;
+                    scorers.push(scorer.with_reference(&fasta, reader.header())?);
+                } else {
+                    scorers.push(scorer);
+                }
+                hets.push(vcf.map(|v| HetSites::load(v, reader.header(), label)).transpose()?);
+                reader.set_threads(r)?;
+                opened.push(Opened::Sam(reader, format));
+            }
+        }
+    }
+    let htslib_formats: Vec<Option<bam::Format>> = opened.iter()
+        .map(|o| match o { Opened::Sam(_, f) => Some(*f), Opened::Paf(..) => None })
+        .collect();
+
+    //output format of every output: --out-format, else the extension of an explicit output path,
+    //else each input's own format; merged output of mixed inputs is PAF/GAF, as PAF/GAF alignments cannot be written as SAM/BAM/CRAM
+    let any_text = formats.iter().any(|f| matches!(f, InputFormat::Paf | InputFormat::Gaf));
+    let default_format = |i: usize| match &args.merged {
+        Some(_) if formats.contains(&InputFormat::Gaf) => OutFormat::Gaf,
+        Some(_) if any_text => OutFormat::Paf,
+        //a merged cram would need one reference holding every input's contigs, so write bam instead
+        Some(_) => match own_format(formats[0], htslib_formats[0]) {
+            OutFormat::Cram => OutFormat::Bam,
+            f => f,
+        },
+        None => own_format(formats[i], htslib_formats[i]),
+    };
+    let n_outputs = if args.merged.is_some() { 1 } else { n };
+    let out_formats: Vec<OutFormat> = (0..n_outputs)
+        .map(|i| args.out_format
+            .or_else(|| args.named_output(i).and_then(OutFormat::from_path))
+            .unwrap_or_else(|| default_format(i)))
+        .collect();
+    //check every input can be written in the format of the output(s) it goes to
+    for i in 0..n {
+        let out_format = if args.merged.is_some() { out_formats[0] } else { out_formats[i] };
+        if out_format.htslib().is_some() && htslib_formats[i].is_none() {
+            return Err(format!("{} is {:?} input, which cannot be written as {:?}; use --out-format paf or gaf",
+                labels[i], formats[i], out_format).into());
+        }
+        //reads are binned from the SEQ/QUAL of SAM/BAM/CRAM records, PAF/GAF has none
+        if out_format.is_reads() && htslib_formats[i].is_none() {
+            return Err(format!("{} is {:?} input, which holds no read sequences to write as {:?}",
+                labels[i], formats[i], out_format).into());
+        }
+    }
+    if args.merged.is_some() && out_formats[0] == OutFormat::Cram {
+        return Err("Merged output cannot be CRAM, as it would need one reference holding every input's contigs; use --out-format bam".into());
+    }
+    if args.bgzf && out_formats.iter().all(|f| f.htslib().is_some() || *f == OutFormat::Ubam) {
+        eprintln!("Warning: --bgzf only applies to PAF/GAF/FASTQ output");
+    }
+    if args.sort_output && out_formats.iter().any(|f| f.is_reads()) {
+        eprintln!("Warning: --sort-output does not apply to FASTQ/unaligned BAM output, reads are written in input order");
+    }
+    if args.has_references() && args.score_source() != ScoreSource::Ref && !htslib_formats.iter().any(|f| matches!(f, Some(bam::Format::Cram))) && !out_formats.contains(&OutFormat::Cram) {
+        //warn user that references will be ignored since no input or output is cram
+        eprintln!("Warning: --refs/--ref1/--ref2/--out-refs/--ref-dir are ignored without CRAM input or output or --score ref");
+    }
+    if (args.cram_version.is_some() || args.embed_ref) && !out_formats.contains(&OutFormat::Cram) {
+        eprintln!("Warning: --cram-version/--embed-ref are ignored without CRAM output");
+    }
+
+    //create one writer per input, named from --out-dir/--prefix and the input label or given by --outputs,
+    //or a single writer holding all inputs' contigs if the user asked for merged output ('-' for stdout)
+    let out_paths = match &args.merged {
+        Some(path) => vec![path.clone()],
+        None => {
+            let extensions: Vec<&str> = out_formats.iter().map(|f| f.extension(args.bgzf)).collect();
+            args.output_paths(&labels, &extensions)?
+        }
+    };
+    let mut outputs: Vec<Output> = Vec::with_capacity(out_formats.len());
+    for (i, (&out_format, out_path)) in out_formats.iter().zip(out_paths.iter()).enumerate() {
+        if out_format.is_reads() {
+            //an unaligned BAM keeps the @RG lines of the inputs written to it
+            let views: Vec<&bam::HeaderView> = opened.iter().enumerate()
+                .filter(|(j, _)| args.merged.is_some() || *j == i)
+                .filter_map(|(_, o)| match o { Opened::Sam(reader, _) => Some(reader.header()), Opened::Paf(..) => None })
+                .collect();
+            let bin = match out_format {
+                OutFormat::Fastq => ReadBin::create_fastq(out_path, compress_output(out_path, args), args)
+                    .map_err(|e| format!("Failed to create output file '{}': {}", out_path, e))?,
+                _ => ReadBin::create_ubam(out_path, &views, args)
+                    .map_err(|e| format!("Failed to create output file '{}': {}", out_path, e))?,
+            };
+            outputs.push(Output::Reads(bin));
+            continue;
+        }
+        let htslib_format = match out_format.htslib() {
+            Some(f) => f,
+            None => {
+                let writer = paf::create_paf(out_path, compress_output(out_path, args), args.level)
+                    .map_err(|e| format!("Failed to create output file '{}': {}", out_path, e))?;
+                let sorter = if args.sort_output { Some(LineSorter::new(args.sort_mem, &args.tmp_dir())) } else { None };
+                outputs.push(Output::Paf { writer, sorter });
+                continue;
+            }
+        };
+        let (header, merged) = if args.merged.is_some() {
+            let views: Vec<&bam::HeaderView> = opened.iter()
+                .filter_map(|o| match o { Opened::Sam(reader, _) => Some(reader.header()), Opened::Paf(..) => None })
+                .collect();
+            let (header, targets) = sam::merged_header(&views, &labels, &args.contig_prefixes(&labels)?)?;
+            (header, Some(targets))
+        } else {
+            //headers are same as in original files, so copy them into output
+            match &opened[i] {
+                Opened::Sam(reader, _) => (bam::Header::from_template(reader.header()), None),
+                Opened::Paf(..) => unreachable!("checked above"),
+            }
+        };
+        let reference = match htslib_format {
+            bam::Format::Cram => resolver.resolve(&bam::HeaderView::from_header(&header), args.out_reference(i), &format!("output {}", out_path))?,
+            _ => None,
+        };
+        let writer = SamWriter::create(out_path, &header, htslib_format, reference.as_deref(), args)
+            .map_err(|e| format!("Failed to create output file '{}': {}", out_path, e))?;
+        outputs.push(Output::Sam { writer, merged });
+    }
+
+    //if any additional threads available, assign to SAM/BAM/CRAM writers
+    //leave leftover threads idle
+    let n_readers = htslib_formats.iter().flatten().count();
+    let n_writers = outputs.iter().filter(|o| matches!(o, Output::Sam { .. } | Output::Reads(ReadBin::Ubam { .. }))).count();
+    let w = (avail_threads - (n_readers * r)) / max(1, n_writers);
+    for out in outputs.iter_mut() {
+        match out {
+            Output::Sam { writer, .. } => writer.set_threads(w)?,
+            Output::Reads(bin) => bin.set_threads(w)?,
+            Output::Paf { .. } => {}
+        }
+    }
+    if n_readers == 0 && n_writers == 0 && args.threads != 8 {
+        eprintln!("Warning: --threads is ignored for PAF/GAF input and output");
+    }
+
+    //records of one read must be adjacent, which coordinate-sorted files do not guarantee,
+    //and merge-join needs every input sorted by read name in the same order
+    //SAM/BAM/CRAM inputs that do not meet this are sorted by read name ourselves, in the order of the name-sorted inputs
+    //PAF inputs cannot be sorted here and are only checked
+    let name_orders: Vec<Option<NameOrder>> = opened.iter()
+        .map(|o| match o { Opened::Sam(reader, _) => sam::header_name_order(reader.header()), Opened::Paf(..) => None })
+        .collect();
+    let target_order = args.name_order
+        .or_else(|| name_orders.iter().flatten().next().copied())
+        .unwrap_or(NameOrder::Natural);
+    let any_coordinate = opened.iter()
+        .any(|o| matches!(o, Opened::Sam(reader, _) if sam::hd_tag(reader.header(), "SO").as_deref() == Some("coordinate")));
+    let merge_order = if args.merge_join { Some(target_order) } else { None };
+
+    //turn every input into a stream of clusters
+    //clusters store all alignments of one read, initial capacity of 10 accounts for supplemental and secondary alignments
+    let mut inputs: Vec<Input> = Vec::with_capacity(n);
+    for (i, o) in opened.into_iter().enumerate() {
+        match o {
+            Opened::Paf(reader, gaf) => {
+                inputs.push(Input::Paf { lines: reader.lines().peekable(), gaf, cluster: Vec::with_capacity(10) });
+            }
+            Opened::Sam(reader, _) => {
+                let header = reader.header().clone();
+                let records: RecordStream<'static> = if (args.merge_join || any_coordinate) && name_orders[i] != Some(target_order) {
+                    eprintln!("Sorting {} by read name in {:?} order (sort order: {})", labels[i], target_order,
+                        sam::hd_tag(&header, "SO").as_deref().unwrap_or("unknown"));
+                    let sorted = crate::sort::sort_records(OwnedRecords(reader), &header, target_order.record_cmp(), args.sort_mem, &args.tmp_dir())
+                        .map_err(|e| format!("Failed to group {} by read name: {}", labels[i], e))?;
+                    Box::new(sorted)
+                } else {
+                    Box::new(OwnedRecords(reader))
+                };
+                inputs.push(Input::Sam { header, records: records.peekable(), cluster: Vec::with_capacity(10), replay: replays[i].take() });
+            }
+        }
+    }
+
+    //inputs whose cluster was used in the last step and must move on to their next read
+    let mut refill = vec![true; n];
+    //last read name of every input, to check the sort order in merge-join mode
+    let mut last_names: Vec<Vec<u8>> = vec![Vec::new(); n];
+
+    //initialize counts for summary statistics printed to terminal
+    let mut summary = crate::Summary::new(n);
+    for input in inputs.iter() {
+        if let Input::Sam { header, .. } = input {
+            summary.set_samples(&sam::read_group_samples(header));
+        }
+    }
+
+    //iterate through all files until they are all exhausted
+    loop {
+
+        //move forward by one read for every file whose last read was used
+        for i in 0..n {
+            if !refill[i] { continue; }
+            inputs[i].next_cluster()?;
+            if let (Some(order), Some(qname)) = (merge_order, inputs[i].qname()) {
+                crate::check_name_order(&mut last_names[i], qname, order, &labels[i])?;
+            }
+        }
+
+        //find the inputs holding the current read (all of them unless merge-joining)
+        let qnames: Vec<Option<&[u8]>> = inputs.iter().map(|input| input.qname()).collect();
+        let present = match crate::sync_streams(&qnames, &labels, merge_order)? {
+            Some(present) => present,
+            None => break, //every file has ended
+        };
+        summary.count_missing(&present);
+        let src0 = present.iter().position(|&p| p).unwrap();
+        let qname = inputs[src0].qname().unwrap().to_vec();
+
+        //get score and number of non-secondary alignment segments for each input holding the read
+        //reads unmapped in (or missing from) an input are not scored
+        let mut scores = Vec::with_capacity(n);
+        for ((input, scorer), &p) in inputs.iter().zip(scorers.iter()).zip(present.iter()) {
+            scores.push(if p { input.score(scorer, args)? } else { None });
+        }
+
+        //return respective winner depending on which score is highest,
+        //ties are a special case that can be determined by user input
+        let (mut winner, mut hapq) = crate::pick_winner(&scores, args);
+        //with --hapq-table, HapQ of reads won by score is that of their bin
+        let mut features = calibration.features(&scores, &winner, args.match_sc, |w| inputs[w].read_length());
+        hapq = calibration.hapq(features, hapq, args.model.cap);
+        //with --het-vcf, reads covering heterozygous sites are assigned by them instead
+        let support = if hets.iter().any(|h| h.is_some()) {
+            let evidence: Vec<Option<crate::SiteEvidence>> = inputs.iter().zip(hets.iter()).zip(present.iter())
+                .map(|((input, h), &p)| h.as_ref().filter(|_| p).and_then(|h| input.site_evidence(h)))
+                .collect();
+            crate::site_support(&evidence)
+        } else {
+            None
+        };
+        if let Some((w, hq)) = support.as_ref().and_then(|s| crate::pick_by_sites(s, &scores, args)) {
+            (winner, hapq) = (w, hq);
+            summary.by_sites += 1;
+            features = None;
+        }
+        calibration.record(&qname, &winner, features);
+        //a read placed ambiguously within the winning input is no more certain than that placement
+        if let (crate::Winner::Best(w), Some(hq)) = (&winner, hapq) {
+            if let Some(cap) = inputs[*w].placement_cap(&scorers[*w], args) {
+                hapq = Some(hq.min(cap));
+            }
+        }
+        summary.count(&winner, hapq, inputs[src0].read_group().as_deref());
+
+        //write the cluster of every winning input (ties and unmapped reads follow user options)
+        let stats = |i: usize| inputs[i].tie_stats(scores[i].map_or(0, |s| s.1));
+        //counted once, also if a tie is written to several bins
+        let mut unbinned = false;
+        for i in crate::destinations(&winner, &qname, &mut ties, stats, unmapped_dest) {
+            //winners always hold the read, but an unmapped read can be missing from the --unmapped input;
+            //then its unmapped record is taken from an input that has it
+            let src = if present[i] { i } else { src0 };
+            //merged output has a single writer, its records are tagged with the label of the input they were assigned to
+            let out = if args.merged.is_some() { 0 } else { i };
+            let label = match winner {
+                crate::Winner::Unmapped => None,
+                _ => args.merged.as_ref().map(|_| labels[i].as_str()),
+            };
+            //sites supporting and conflicting with the input the read is written to
+            let sites = support.filter(|_| present[i]).map(|(counts, _)| (counts[i], counts[1 - i]));
+            if !write_cluster(&mut outputs[out], &mut inputs[src], src, hapq, label, args.chain, sites)? {
+                unbinned = true;
+            }
+        }
+        if unbinned {
+            summary.count_unbinned(&qname);
+        }
+
+        refill = present;
+    }
+    for out in outputs {
+        out.finish()?;
+    }
+    //print summary statistics to terminal
+    summary.print(&labels);
+    if let Some(path) = &args.summary_tsv {
+        summary.write_tsv(path, &labels).map_err(|e| format!("Failed to write summary '{}': {}", path, e))?;
+    }
+    calibration.finish(args)?;
+    Ok(())
+}
@@ -1,44 +1,451 @@
 pub mod cli;
-pub use cli::Cli;
+pub use cli::{Cli, InputFormat, MultimapSignal, NameOrder, OutFormat, TieCriterion};
+pub use calibrate::HapqModel;
 pub mod paf;
 pub mod sam;
+pub mod sort;
+pub mod refs;
+pub mod diploid;
+pub mod align;
+pub mod inputs;
+pub mod bins;
+pub mod score;
+pub mod het;
+pub mod calibrate;
+pub mod ties;
+use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use flate2::read::MultiGzDecoder;
 use twox_hash::XxHash64;
 
+//open a text input ('-' for stdin) such as PAF, FASTA, VCF or TSV,
+//decompressing gzip/BGZF transparently
+//compression is detected from the magic bytes, so this also works on pipes
+pub(crate) fn open_text(path: &str) -> io::Result<Box<dyn BufRead>> {
+    let raw: Box<dyn Read> = if path == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(path)?)
+    };
+    text_reader(raw)
+}
+
+//read lines from a stream, decompressing gzip/BGZF
+pub(crate) fn text_reader(raw: Box<dyn Read>) -> io::Result<Box<dyn BufRead>> {
+    let mut reader = BufReader::new(raw);
+    //BGZF is multi-member gzip, so MultiGzDecoder reads both
+    if reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
 //enum to store best alignment or read
+//inputs are referred to by their index in Cli::inputs
 pub enum Winner {
-    Asm1,
-    Asm2,
-    Both,
+    Best(usize),
+    Tied(Vec<usize>),
     Unmapped,
 }
 
-//if read has identical alignment to both haps,
-//chose which hap to report randomly with equal likelihoods
-//use last bit of hash of read ID (as bytes) as random assignment
-pub fn choose_random(id: &[u8]) -> Winner {
+//if read has identical alignment to several inputs,
+//chose which one to report randomly with equal likelihoods
+//use hash of read ID (as bytes) modulo number of tied inputs as random assignment
+pub fn choose_random(id: &[u8], tied: &[usize], seed: u64) -> usize {
     //XxHash64 provides reproducible assignment bc is deterministic
-    let mut hasher = XxHash64::with_seed(42);
+    let mut hasher = XxHash64::with_seed(seed);
     id.hash(&mut hasher);
-    if hasher.finish() & 1 == 0 { Winner::Asm1 } else { Winner::Asm2 }
+    tied[(hasher.finish() % tied.len() as u64) as usize]
+}
+
+//choose the winning input from the weighted score of each cluster
+//scores are (weighted score, non-secondary splits), None if the read is unmapped in that input
+//HAPQ is computed from the best and second-best scores
+pub fn pick_winner(scores: &[Option<(f32, u32)>], args: &Cli) -> (Winner, Option<u8>) {
+    let mapped: Vec<usize> = (0..scores.len()).filter(|&i| scores[i].is_some()).collect();
+
+    match mapped.len() {
+        0 => return (Winner::Unmapped, None), //unmapped everywhere
+        //if read only maps to one input then that input is the winner
+        1 => return (Winner::Best(mapped[0]), if args.no_hapq { None } else { Some(args.model.one_sided.min(args.model.cap)) }),
+        _ => {} //mapped in several inputs, compare scores below
+    }
+
+    //find best score and every input that reaches it
+    let best = mapped.iter().map(|&i| scores[i].unwrap().0).fold(f32::MIN, f32::max);
+    let tied: Vec<usize> = mapped.iter().copied().filter(|&i| scores[i].unwrap().0 == best).collect();
+    if tied.len() > 1 {
+        let hapq = if args.no_hapq { None } else { Some(0u8) };
+        return (Winner::Tied(tied), hapq);
+    }
+
+    //single winner, HAPQ against the runner-up
+    let (score_w, n_splits_w) = scores[tied[0]].unwrap();
+    let second = mapped.iter().filter(|&&i| i != tied[0]).map(|&i| scores[i].unwrap().0).fold(f32::MIN, f32::max);
+    let hapq = if args.no_hapq { None } else { Some(compute_hapq(score_w, second, n_splits_w, args.match_sc, &args.model)) };
+    (Winner::Best(tied[0]), hapq)
+}
+
+//evidence of a read at the heterozygous sites of --het-vcf, from its alignments to one haplotype:
+//sites where the read shows that haplotype's allele (own) or the other haplotype's (other), with their base qualities summed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SiteEvidence {
+    pub own: u32,
+    pub own_qual: u32,
+    pub other: u32,
+    pub other_qual: u32,
+}
+
+//sites supporting each of two haplotypes and their summed base qualities, as ([sites], [qualities])
+//evidence is per haplotype, None where the read is not aligned or has no VCF; the alignments to both haplotypes
+//see the same read bases at a site, so only the side with more evidence is used rather than counting sites twice
+//None if the read covers no site
+pub fn site_support(evidence: &[Option<SiteEvidence>]) -> Option<([u32; 2], [u32; 2])> {
+    let (side, ev) = evidence.iter().enumerate()
+        .filter_map(|(i, e)| e.map(|e| (i, e)))
+        .max_by_key(|(i, e)| (e.own_qual + e.other_qual, e.own + e.other, std::cmp::Reverse(*i)))?;
+    if ev.own + ev.other == 0 {
+        return None;
+    }
+    Some(if side == 0 {
+        ([ev.own, ev.other], [ev.own_qual, ev.other_qual])
+    } else {
+        ([ev.other, ev.own], [ev.other_qual, ev.own_qual])
+    })
+}
+
+//choose one of two haplotypes by the summed base qualities of the sites supporting each, HapQ being their difference
+//None if the sites favour a haplotype the read is not aligned to, or are balanced while it aligns to one only:
+//the read is then assigned by alignment score
+pub fn pick_by_sites(support: &([u32; 2], [u32; 2]), scores: &[Option<(f32, u32)>], args: &Cli) -> Option<(Winner, Option<u8>)> {
+    let quals = support.1;
+    let winner = match quals[0].cmp(&quals[1]) {
+        std::cmp::Ordering::Greater => 0,
+        std::cmp::Ordering::Less => 1,
+        std::cmp::Ordering::Equal if scores.iter().all(|s| s.is_some()) => {
+            return Some((Winner::Tied(vec![0, 1]), if args.no_hapq { None } else { Some(0) }));
+        }
+        std::cmp::Ordering::Equal => return None,
+    };
+    scores[winner]?;
+    let hapq = (quals[winner] - quals[1 - winner]).min(args.model.cap as u32) as u8;
+    Some((Winner::Best(winner), if args.no_hapq { None } else { Some(hapq) }))
+}
+
+//resolve a Winner into the inputs whose cluster should be written
+//ties are resolved by the TieBreaker, from the stats of the alignments to every tied input
+pub fn destinations(winner: &Winner, qname: &[u8], ties: &mut ties::TieBreaker, stats: impl Fn(usize) -> ties::TieStats, unmapped_dest: Option<usize>) -> Vec<usize> {
+    match winner {
+        Winner::Best(i) => {
+            ties.won(*i, stats);
+            vec![*i]
+        }
+        Winner::Tied(tied) => ties.resolve(tied, qname, stats),
+        Winner::Unmapped => unmapped_dest.into_iter().collect(),
+    }
+}
+
+//decide which inputs hold the current read, given the read name of each input's next cluster (None at end of file)
+//without a merge-join order every input must hold the same read, otherwise the streams are out of sync
+//with one, the inputs holding the smallest read name take part and the others are missing that read
+//returns None once every input has ended
+pub fn sync_streams(qnames: &[Option<&[u8]>], labels: &[String], merge_order: Option<NameOrder>) -> Result<Option<Vec<bool>>, String> {
+    // end of file reached for all, should occur at same iteration
+    if qnames.iter().all(|q| q.is_none()) {
+        return Ok(None);
+    }
+
+    if let Some(order) = merge_order {
+        let first = qnames.iter().flatten().copied()
+            .min_by(|a, b| order.cmp_names(a, b)).unwrap();
+        return Ok(Some(qnames.iter().map(|q| matches!(q, Some(q) if order.cmp_names(q, first).is_eq())).collect()));
+    }
+
+    // check for possible errors such as:
+    //end of file / clusters don't represent same read in every file
+    let ended: Vec<&String> = (0..qnames.len()).filter(|&i| qnames[i].is_none()).map(|i| &labels[i]).collect();
+    if !ended.is_empty() {
+        //some files have ended earlier than the others- throw error
+        return Err(format!("alignment streams out of sync: {:?} ended earlier (use --merge-join if reads can be missing from some inputs)", ended));
+    }
+    //read ID is not the same in all clusters- throw error
+    let id0 = qnames[0].unwrap();
+    for i in 1..qnames.len() {
+        let id = qnames[i].unwrap();
+        if id != id0 {
+            return Err(format!("alignment streams out of sync: {}={} {}={} (use --merge-join if reads can be missing from some inputs)",
+                labels[0], String::from_utf8_lossy(id0), labels[i], String::from_utf8_lossy(id)));
+        }
+    }
+    Ok(Some(vec![true; qnames.len()]))
+}
+
+//merge-join needs every input sorted by read name, check each new read name against the previous one
+pub fn check_name_order(last: &mut Vec<u8>, qname: &[u8], order: NameOrder, label: &str) -> Result<(), String> {
+    if !last.is_empty() && order.cmp_names(qname, last).is_le() {
+        return Err(format!("{} is not sorted by read name in {:?} order ('{}' follows '{}'); see --name-order",
+            label, order, String::from_utf8_lossy(qname), String::from_utf8_lossy(last)));
+    }
+    last.clear();
+    last.extend_from_slice(qname);
+    Ok(())
+}
+
+//read counts of one group of reads: all reads, one read group or one sample
+#[derive(Clone)]
+pub struct Counts {
+    pub assigned: Vec<u64>,
+    pub equal: u64,
+    pub unmapped: u64,
+    //number of reads with every HapQ value 0-60
+    pub hapq: Vec<u64>,
+}
+
+impl Counts {
+    pub fn new(n_inputs: usize) -> Self {
+        Counts { assigned: vec![0; n_inputs], equal: 0, unmapped: 0, hapq: vec![0; 61] }
+    }
+
+    pub fn count(&mut self, winner: &Winner, hapq: Option<u8>) {
+        match winner {
+            Winner::Best(i) => self.assigned[*i] += 1,
+            Winner::Tied(_) => self.equal += 1,
+            Winner::Unmapped => self.unmapped += 1,
+        }
+        if let Some(hq) = hapq {
+            self.hapq[hq.min(60) as usize] += 1;
+        }
+    }
+
+    pub fn add(&mut self, other: &Counts) {
+        for (a, b) in self.assigned.iter_mut().zip(other.assigned.iter()) { *a += b; }
+        self.equal += other.equal;
+        self.unmapped += other.unmapped;
+        for (a, b) in self.hapq.iter_mut().zip(other.hapq.iter()) { *a += b; }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.assigned.iter().sum::<u64>() + self.equal + self.unmapped
+    }
+
+    //mean and median HapQ, None if no read got one (all unmapped, or --no-hapq)
+    pub fn hapq_mean_median(&self) -> Option<(f64, u8)> {
+        let n: u64 = self.hapq.iter().sum();
+        if n == 0 {
+            return None;
+        }
+        let sum: u64 = self.hapq.iter().enumerate().map(|(q, &c)| q as u64 * c).sum();
+        let mut seen = 0;
+        let median = self.hapq.iter().position(|&c| { seen += c; seen * 2 >= n }).unwrap_or(0);
+        Some((sum as f64 / n as f64, median as u8))
+    }
+}
+
+//HapQ bins of the summary TSV, as (first, last) HapQ
+const HAPQ_BINS: [(usize, usize); 8] = [(0, 0), (1, 9), (10, 19), (20, 29), (30, 39), (40, 49), (50, 59), (60, 60)];
+
+//read counts for the summary printed to terminal at the end of a run
+pub struct Summary {
+    pub total: Counts,
+    //reads missing from each input in merge-join mode
+    pub missing: Vec<u64>,
+    //counts per read group (RG tag, '*' for reads without one), in order of first appearance
+    pub groups: Vec<(String, Counts)>,
+    group_index: HashMap<String, usize>,
+    //read group IDs of the input headers with their sample (SM), in header order
+    samples: Vec<(String, String)>,
+    //reads assigned by their heterozygous sites rather than by alignment score (--het-vcf)
+    pub by_sites: u64,
+    //reads left out of read bins as none of their records holds the full sequence
+    pub unbinned: u64,
+}
+
+impl Summary {
+    pub fn new(n_inputs: usize) -> Self {
+        Summary { total: Counts::new(n_inputs), missing: vec![0; n_inputs], groups: Vec::new(), group_index: HashMap::new(), samples: Vec::new(), by_sites: 0, unbinned: 0 }
+    }
+
+    //read group IDs and their samples, from the @RG lines of the input headers
+    pub fn set_samples(&mut self, samples: &[(String, String)]) {
+        for (id, sm) in samples {
+            if !self.samples.iter().any(|(known, _)| known == id) {
+                self.samples.push((id.clone(), sm.clone()));
+            }
+        }
+    }
+
+    //a read that could not be binned, warned about the first time
+    pub fn count_unbinned(&mut self, qname: &[u8]) {
+        if self.unbinned == 0 {
+            eprintln!("Warning: read '{}' is hard clipped or without SEQ in every record, so it is not binned; such reads are counted in the summary",
+                String::from_utf8_lossy(qname));
+        }
+        self.unbinned += 1;
+    }
+
+    pub fn count_missing(&mut self, present: &[bool]) {
+        for (missing, &p) in self.missing.iter_mut().zip(present.iter()) {
+            if !p { *missing += 1; }
+        }
+    }
+
+    pub fn count(&mut self, winner: &Winner, hapq: Option<u8>, read_group: Option<&str>) {
+        self.total.count(winner, hapq);
+        let rg = read_group.unwrap_or("*");
+        let i = match self.group_index.get(rg) {
+            Some(&i) => i,
+            None => {
+                self.group_index.insert(rg.to_string(), self.groups.len());
+                self.groups.push((rg.to_string(), Counts::new(self.total.assigned.len())));
+                self.groups.len() - 1
+            }
+        };
+        self.groups[i].1.count(winner, hapq);
+    }
+
+    //counts per read group in header order (then in order of appearance), empty if no read carries an RG tag
+    fn read_groups(&self) -> Vec<&(String, Counts)> {
+        if self.groups.iter().all(|(rg, _)| rg == "*") {
+            return Vec::new();
+        }
+        let mut groups: Vec<&(String, Counts)> = self.groups.iter().collect();
+        groups.sort_by_key(|(rg, _)| self.samples.iter().position(|(id, _)| id == rg).unwrap_or(usize::MAX));
+        groups
+    }
+
+    fn sample_of(&self, rg: &str) -> &str {
+        self.samples.iter().find(|(id, _)| id == rg).map(|(_, sm)| sm.as_str()).unwrap_or("*")
+    }
+
+    //counts per sample, summed over its read groups; empty unless the read groups belong to several samples
+    fn samples(&self) -> Vec<(String, Counts)> {
+        let mut samples: Vec<(String, Counts)> = Vec::new();
+        for (rg, counts) in self.read_groups() {
+            let sm = self.sample_of(rg);
+            match samples.iter_mut().find(|(s, _)| s == sm) {
+                Some((_, c)) => c.add(counts),
+                None => samples.push((sm.to_string(), counts.clone())),
+            }
+        }
+        if samples.len() > 1 { samples } else { Vec::new() }
+    }
+
+    pub fn print(&self, labels: &[String]) {
+        let total = self.total.total();
+        let pct = |n: u64| n as f64 / total as f64 * 100.0;
+        for (label, &n) in labels.iter().zip(self.total.assigned.iter()) {
+            eprintln!("Reads aligned better to {}: {} ({:.1}%)", label, n, pct(n));
+        }
+        eprintln!("Reads with equal scores:     {} ({:.1}%)", self.total.equal, pct(self.total.equal));
+        eprintln!("Reads unmapped to all:       {} ({:.1}%)", self.total.unmapped, pct(self.total.unmapped));
+        eprintln!("Total reads parsed:          {}", total);
+        if self.by_sites > 0 {
+            eprintln!("Reads assigned by het sites: {} ({:.1}%)", self.by_sites, pct(self.by_sites));
+        }
+        if self.unbinned > 0 {
+            eprintln!("Reads not binned (no full SEQ): {} ({:.1}%)", self.unbinned, pct(self.unbinned));
+        }
+        for (label, &n) in labels.iter().zip(self.missing.iter()) {
+            if n > 0 {
+                eprintln!("Reads missing from {}: {} ({:.1}%)", label, n, pct(n));
+            }
+        }
+        //one line per read group, and per sample if they differ, to spot groups with an unusual balance
+        for (rg, counts) in self.read_groups() {
+            eprintln!("Read group {} (sample {}): {}", rg, self.sample_of(rg), Summary::group_line(counts, labels));
+        }
+        for (sm, counts) in self.samples() {
+            eprintln!("Sample {}: {}", sm, Summary::group_line(&counts, labels));
+        }
+    }
+
+    //counts of one read group or sample on one line
+    fn group_line(counts: &Counts, labels: &[String]) -> String {
+        let total = counts.total();
+        let pct = |n: u64| n as f64 / total as f64 * 100.0;
+        let mut fields: Vec<String> = labels.iter().zip(counts.assigned.iter())
+            .map(|(label, &n)| format!("{} {} ({:.1}%)", label, n, pct(n)))
+            .collect();
+        fields.push(format!("equal {} ({:.1}%)", counts.equal, pct(counts.equal)));
+        fields.push(format!("unmapped {} ({:.1}%)", counts.unmapped, pct(counts.unmapped)));
+        if let Some((mean, median)) = counts.hapq_mean_median() {
+            fields.push(format!("HapQ mean {:.1}, median {}", mean, median));
+        }
+        fields.join(", ")
+    }
+
+    //the summary as TSV: one row for all reads, one per read group and one per sample (if several),
+    //with the reads assigned to every input, HapQ mean/median (NA without HapQ) and reads per HapQ bin
+    pub fn write_tsv(&self, path: &str, labels: &[String]) -> std::io::Result<()> {
+        let mut out = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let mut header = vec!["level".to_string(), "id".into(), "sample".into(), "reads".into()];
+        header.extend(labels.iter().cloned());
+        header.extend(["equal".to_string(), "unmapped".into(), "hapq_mean".into(), "hapq_median".into()]);
+        header.extend(HAPQ_BINS.iter().map(|&(a, b)| if a == b { format!("hapq_{}", a) } else { format!("hapq_{}_{}", a, b) }));
+        writeln!(out, "{}", header.join("\t"))?;
+
+        let mut rows: Vec<(&str, &str, &str, &Counts)> = vec![("all", "*", "*", &self.total)];
+        for (rg, counts) in self.read_groups() {
+            rows.push(("read_group", rg, self.sample_of(rg), counts));
+        }
+        let samples = self.samples();
+        for (sm, counts) in samples.iter() {
+            rows.push(("sample", sm, sm, counts));
+        }
+        for (level, id, sample, counts) in rows {
+            let mut fields = vec![level.to_string(), id.to_string(), sample.to_string(), counts.total().to_string()];
+            fields.extend(counts.assigned.iter().map(|n| n.to_string()));
+            fields.push(counts.equal.to_string());
+            fields.push(counts.unmapped.to_string());
+            match counts.hapq_mean_median() {
+                Some((mean, median)) => fields.extend([format!("{:.2}", mean), median.to_string()]),
+                None => fields.extend(["NA".to_string(), "NA".to_string()]),
+            }
+            fields.extend(HAPQ_BINS.iter().map(|&(a, b)| counts.hapq[a..=b].iter().sum::<u64>().to_string()));
+            writeln!(out, "{}", fields.join("\t"))?;
+        }
+        out.flush()
+    }
 }
 
 //compute haplotype assignment quality (HAPQ) score
 //modeled on BWA-MEM's mem_approx_mapq_se (bwamem.c)
 //confidence measure that a read was assigned to the correct haplotype
-pub fn compute_hapq(score_winner: f32, score_loser: f32, n_splits: u32, match_sc: f32) -> u8 {
+//slope, split threshold and cap come from the HapQ model (--hapq-model)
+pub fn compute_hapq(score_winner: f32, score_loser: f32, n_splits: u32, match_sc: f32, model: &HapqModel) -> u8 {
     if score_winner <= 0.0 {
         return 0;
     }
     //approximately the difference in matching bases btwn alignment1 and alignment2
     let diff = (score_winner - score_loser) / match_sc;
-    //penalize reads with more that 3 split aligments (likely a complex region)
-    let pen_split = if n_splits <= 3 { 1.0 } else { 3.0 / n_splits as f32 };
-    let score = 6.02 * diff * pen_split;
-    score.clamp(0.0, 60.0) as u8
+    //penalize reads with more split aligments than the threshold, 3 by default (likely a complex region)
+    let pen_split = if n_splits <= model.split_threshold { 1.0 } else { model.split_threshold as f32 / n_splits as f32 };
+    let score = model.slope * diff * pen_split;
+    score.clamp(0.0, model.cap as f32) as u8
     
 }
 
+//what the HapQ of a read won by score is computed from, by which reads are binned for calibration (--calibrate, --hapq-table)
+#[derive(Debug, Clone, Copy)]
+pub struct HapqFeatures {
+    //score difference to the runner-up in bases (as in compute_hapq), None if the read maps to the winner only
+    pub diff: Option<f32>,
+    //non-secondary splits on the winning side
+    pub splits: u32,
+    pub read_len: u32,
+}
+
+//HapQ features of a read whose winner was picked by score, None unless it has a single winner
+pub fn hapq_features(scores: &[Option<(f32, u32)>], winner: &Winner, match_sc: f32, read_len: u32) -> Option<HapqFeatures> {
+    let Winner::Best(w) = *winner else { return None };
+    let (score_w, splits) = scores[w]?;
+    let second = scores.iter().enumerate().filter(|&(i, _)| i != w).filter_map(|(_, s)| s.map(|s| s.0)).reduce(f32::max);
+    Some(HapqFeatures { diff: second.map(|s| (score_w - s) / match_sc), splits, read_len })
+}
+
 //helper function to merge any read alignment segments that overlap in read coordinates
 //returns count of unique bps of the read contained in an alignment segment
 pub fn merge_intervals(intervals: &mut Vec<(u32, u32)>) -> u32 {
@@ -69,4 +476,114 @@ pub fn merge_intervals(intervals: &mut Vec<(u32, u32)>) -> u32 {
     }
     return read_bps_aligned; 
 }
- 
\ No newline at end of file
+ 
+//one non-secondary alignment segment of a read, to judge whether the segments of a read form one collinear chain
+//query coordinates are on the read as sequenced, target coordinates on the forward strand of the target
+pub struct Segment<T> {
+    pub qstart: u32,
+    pub qend: u32,
+    pub target: T,
+    pub reverse: bool,
+    pub tstart: i64,
+    pub tend: i64,
+}
+
+//how two segments that follow each other on the read are placed on the reference
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Junction {
+    //the next segment continues further along the same strand of the same contig
+    Collinear,
+    //on another contig
+    Contig,
+    //on the other strand of the same contig
+    Strand,
+    //back before the start of the previous segment
+    Order,
+    //overlapping the previous segment on the reference by more than they overlap on the read
+    Overlap,
+}
+
+impl Junction {
+    //letter of the junction in the hc tag
+    pub fn code(self) -> char {
+        match self {
+            Junction::Collinear => '.',
+            Junction::Contig => 'C',
+            Junction::Strand => 'S',
+            Junction::Order => 'B',
+            Junction::Overlap => 'O',
+        }
+    }
+}
+
+//junctions between the segments of a read, in read order
+pub fn chain_junctions<T: PartialEq>(segments: &mut [Segment<T>]) -> Vec<Junction> {
+    segments.sort_by_key(|s| (s.qstart, s.qend));
+    segments.windows(2).map(|pair| {
+        let (prev, next) = (&pair[0], &pair[1]);
+        if prev.target != next.target {
+            return Junction::Contig;
+        }
+        if prev.reverse != next.reverse {
+            return Junction::Strand;
+        }
+        //bases both segments align, which may be aligned twice on the reference as well
+        let read_overlap = prev.qend.saturating_sub(next.qstart) as i64;
+        //on the reverse strand the read runs towards the start of the contig
+        let (backward, ref_overlap) = if prev.reverse {
+            (next.tend > prev.tend, next.tend - prev.tstart)
+        } else {
+            (next.tstart < prev.tstart, prev.tend - next.tstart)
+        };
+        if backward {
+            Junction::Order
+        } else if ref_overlap > read_overlap {
+            Junction::Overlap
+        } else {
+            Junction::Collinear
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn seg(qstart: u32, qend: u32, target: &str, reverse: bool, tstart: i64, tend: i64) -> Segment<&str> {
+        Segment { qstart, qend, target, reverse, tstart, tend }
+    }
+
+    #[test]
+    fn reverse_strand_chains_run_towards_the_contig_start() {
+        //read order, not input order, decides the junctions
+        let mut segments = [seg(500, 1000, "c", true, 500, 1000), seg(0, 500, "c", true, 1000, 1500)];
+        assert_eq!(chain_junctions(&mut segments), [Junction::Collinear]);
+        //on the reverse strand, a next split further along the contig goes backwards
+        let mut segments = [seg(0, 500, "c", true, 1000, 1500), seg(500, 1000, "c", true, 1200, 1700)];
+        assert_eq!(chain_junctions(&mut segments), [Junction::Order]);
+        let mut segments = [seg(0, 500, "c", false, 1000, 1500), seg(500, 1000, "c", false, 500, 1000)];
+        assert_eq!(chain_junctions(&mut segments), [Junction::Order]);
+    }
+
+    #[test]
+    fn overlaps_count_beyond_the_read_overlap() {
+        //splits sharing 50 read bases may share 50 reference bases, on either strand
+        let mut segments = [seg(0, 500, "c", true, 1000, 1500), seg(450, 1000, "c", true, 500, 1050)];
+        assert_eq!(chain_junctions(&mut segments), [Junction::Collinear]);
+        let mut segments = [seg(0, 500, "c", true, 1000, 1500), seg(450, 1000, "c", true, 500, 1100)];
+        assert_eq!(chain_junctions(&mut segments), [Junction::Overlap]);
+        let mut segments = [seg(0, 500, "c", false, 1000, 1500), seg(450, 1000, "c", false, 1450, 2000)];
+        assert_eq!(chain_junctions(&mut segments), [Junction::Collinear]);
+    }
+
+    #[test]
+    fn contig_and_strand_switches() {
+        let mut segments = [
+            seg(0, 300, "c", false, 0, 300),
+            seg(300, 600, "d", false, 300, 600),
+            seg(600, 900, "d", true, 0, 300),
+            seg(900, 1200, "d", true, 300, 600),
+        ];
+        assert_eq!(chain_junctions(&mut segments), [Junction::Contig, Junction::Strand, Junction::Order]);
+    }
+}
@@ -1,21 +1,25 @@
 use clap::Parser;
-use diplinator::{Cli, paf, sam};
+use diplinator::{Cli, OutFormat, align, diploid, inputs};
 use std::time::Instant;
 
 fn main() -> Result<(), Box<dyn std::error::Error>>  {
     let start = Instant::now();
-    let args = Cli::parse(); 
+    let mut args = Cli::parse(); 
+    args.check_inputs()?;
+    args.resolve_hapq_model()?;
     
-    if args.paf {
-        if args.threads != 8 {
-            eprintln!("Warning: --threads is ignored in PAF mode");
+    if args.diploid_mode() {
+        if args.bgzf && args.out_format != Some(OutFormat::Fastq) {
+            eprintln!("Warning: --bgzf only applies to FASTQ output with --hap-map/--hap-tags");
         }
-        if args.ref1.is_some() || args.ref2.is_some() {
-            eprintln!("Warning: --ref1/--ref2 are ignored in PAF mode");
-        }
-        paf::process_paf(&args)?;
+        diploid::process_diploid(&args)?;
+    } else if let Some(reads) = &args.reads {
+        //inputs are FASTAs, the alignments come straight from the aligners
+        let aligners = align::spawn_aligners(&args, reads)?;
+        let paths = aligners.paths.clone();
+        aligners.finish(inputs::process_inputs(&args, &paths))?;
     } else {
-        sam::process_sam(&args)?;
+        inputs::process_inputs(&args, &args.inputs)?;
     }
 
     let duration = start.elapsed();
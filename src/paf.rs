@@ -1,150 +1,34 @@
 use std::{
+    borrow::Cow,
     fs::File,
-    io::{self, BufRead, BufReader, Write, BufWriter},
+    io::{self, Write, BufWriter},
     iter::Peekable,
 };
-use crate::cli::Cli;
-
-
-pub fn process_paf(args: &Cli) -> Result<(), Box<dyn std::error::Error>> {
-
-    // read in both files
-    let file1 = File::open(&args.asm1)
-        .map_err(|e| format!("Failed to open asm1 file '{}': {}", args.asm1, e))?;
-    let file2 = File::open(&args.asm2)
-        .map_err(|e| format!("Failed to open asm2 file '{}': {}", args.asm2, e))?;
-
-    //create peekable iterators of each file (line-by-line for PAF)
-    let mut asm1_iter = BufReader::new(file1).lines().peekable();
-    let mut asm2_iter = BufReader::new(file2).lines().peekable();
-
-    //create writers for both outputs that share user specified prefix
-    let asm1_out_path = format!("diplinator_{}.paf", args.s1);
-    let asm2_out_path = format!("diplinator_{}.paf", args.s2);
-    let mut out_asm1 = BufWriter::new(File::create(&asm1_out_path)
-        .map_err(|e| format!("Failed to create output file '{}': {}", asm1_out_path, e))?);
-    let mut out_asm2 = BufWriter::new(File::create(&asm2_out_path)
-        .map_err(|e| format!("Failed to create output file '{}': {}", asm2_out_path, e))?);
-
-    //vectors that store all alignments of one read (cluster of alignments)
-    //initialize capacity to 10 to account for supplemental and secondary alignments
-    let mut cluster_asm1: Vec<String> = Vec::with_capacity(10);
-    let mut cluster_asm2: Vec<String> = Vec::with_capacity(10);
-
-    //initialize counts for summary statistics printed to terminal
-    let mut count_asm1: u64 = 0;
-    let mut count_asm2: u64 = 0;
-    let mut count_equal: u64 = 0;
-    let mut count_unmapped: u64 = 0;
-
-    //iterate through both files until they are both exhausted
-    while asm1_iter.peek().is_some() || asm2_iter.peek().is_some() {
-
-        //move forward by one read for both files
-        get_clusters(&mut asm1_iter, &mut cluster_asm1)?;
-        get_clusters(&mut asm2_iter, &mut cluster_asm2)?;
-
-        // check for possible errors such as:
-        //end of file / empty cluster / clusters don't represent same read in both files
-        match (cluster_asm1.first(), cluster_asm2.first()) {
-            (None, None) => break,           // end of file reached for both, should occur at same iteration
-            (Some(_), None) | (None, Some(_)) => {
-                //one file has ended earlier than the other- throw error
-                return Err("PAF streams out of sync: one file ended earlier".into());
-            }
-            (Some(m), Some(p)) => {
-                //read ID is not the same in both clusters- throw error
-                let id1 = m.split('\t').next().unwrap_or("");
-                let id2 = p.split('\t').next().unwrap_or("");
-                if id1 != id2 {
-                    return Err(format!(
-                        "PAF streams out of sync: asm1={} asm2={}", id1, id2
-                    ).into());
-                }
-            }
-        }
+use rust_htslib::bgzf;
+use crate::cli::{Cli, MultimapSignal};
+use crate::score::Scorer;
+use crate::Segment;
+use crate::ties::TieStats;
+
 
-        //get cluster with the higher alignment score, returns the Winner enum and HAPQ
-        let (winner, hapq) = compare_clusters(&mut cluster_asm1, &mut cluster_asm2, args)?;
-
-        //logic for which file to write read to given score comparison output
-        //helper: format hq tag suffix if hapq is present
-        let hq_suffix = match hapq {
-            Some(hq) => format!("\thq:i:{}", hq),
-            None => String::new(),
-        };
-
-        match winner {
-            //asm1 clear winner, write to out_asm1
-            crate::Winner::Asm1 => {
-                count_asm1 += 1; // increment read counter
-                for rec in cluster_asm1.iter_mut() {
-                    writeln!(out_asm1, "{}{}", rec, hq_suffix)?;
-                }
-            }
-            //asm2 clear winner, write to out_asm2
-            crate::Winner::Asm2 => {
-                count_asm2 += 1; // increment read counter
-                for rec in cluster_asm2.iter_mut() {
-                    writeln!(out_asm2, "{}{}", rec, hq_suffix)?;
-                }
-            }
-            crate::Winner::Both => {
-                count_equal += 1; // increment read counter
-                //if user specifies --both, write equal scoring reads to both output files
-                if args.both {
-                    for rec in cluster_asm1.iter_mut() {
-                        writeln!(out_asm1, "{}{}", rec, hq_suffix)?;
-                    }
-                    for rec in cluster_asm2.iter_mut() {
-                        writeln!(out_asm2, "{}{}", rec, hq_suffix)?;
-                    }
-                //default behavior is randomly assign equal scoring read to one file
-                } else {
-                    //hash read name and use last bit value to assign to asm1 or asm2
-                    //ensures that assignments will be reproducible
-                    let qname = cluster_asm1[0].split('\t').next().unwrap();
-                    match crate::choose_random(qname.as_bytes()) {
-                        crate::Winner::Asm1 => {
-                            for rec in cluster_asm1.iter_mut() {
-                                writeln!(out_asm1, "{}{}", rec, hq_suffix)?;
-                            }
-                        }
-                        _ => {
-                            for rec in cluster_asm2.iter_mut() {
-                                writeln!(out_asm2, "{}{}", rec, hq_suffix)?;
-                            }
-                        }
-                    }
-                }
-            }
-            crate::Winner::Unmapped => {
-                count_unmapped += 1;
-                match args.unmapped {
-                    crate::cli::UnmappedDest::Asm1 => {
-                        for rec in cluster_asm1.iter_mut() { writeln!(out_asm1, "{}{}", rec, hq_suffix)?; }
-                    }
-                    crate::cli::UnmappedDest::Asm2 => {
-                        for rec in cluster_asm2.iter_mut() { writeln!(out_asm2, "{}{}", rec, hq_suffix)?; }
-                    }
-                    crate::cli::UnmappedDest::Discard => {}
-                }
-            }
-        }
 
+//create a PAF output ('-' for stdout), BGZF-compressed if requested
+pub(crate) fn create_paf(path: &str, compress: bool, level: Option<u8>) -> Result<Box<dyn Write>, Box<dyn std::error::Error>> {
+    let level = match level {
+        Some(l) => bgzf::CompressionLevel::Level(l as i8),
+        None => bgzf::CompressionLevel::Default,
+    };
+    match (path, compress) {
+        ("-", false) => Ok(Box::new(BufWriter::new(io::stdout()))),
+        ("-", true) => Ok(Box::new(bgzf::Writer::from_stdout_with_compression(level)?)),
+        (_, false) => Ok(Box::new(BufWriter::new(File::create(path)?))),
+        (_, true) => Ok(Box::new(bgzf::Writer::from_path_with_level(path, level)?)),
     }
-    //print summary statistics to terminal
-    let total = count_asm1 + count_asm2 + count_equal + count_unmapped;
-    eprintln!("Reads aligned better to {}: {} ({:.1}%)", args.s1, count_asm1, count_asm1 as f64 / total as f64 * 100.0);
-    eprintln!("Reads aligned better to {}: {} ({:.1}%)", args.s2, count_asm2, count_asm2 as f64 / total as f64 * 100.0);
-    eprintln!("Reads with equal scores:     {} ({:.1}%)", count_equal, count_equal as f64 / total as f64 * 100.0);
-    eprintln!("Reads unmapped to both:      {} ({:.1}%)", count_unmapped, count_unmapped as f64 / total as f64 * 100.0);
-    eprintln!("Total reads parsed:          {}", total);
-    Ok(())
 }
 
+
 //function to move ahead one read group at a time for PAF
-fn get_clusters<I>(lines: &mut Peekable<I>, cluster: &mut Vec<String>)-> io::Result<()>
+pub(crate) fn get_clusters<I>(lines: &mut Peekable<I>, cluster: &mut Vec<String>)-> io::Result<()>
 where
     I: Iterator<Item= Result<String, std::io::Error>>,
 {
@@ -200,13 +84,56 @@ where
 }
 
 
-//helper function to get weighted score of split reads using a specified tag (AS or ms)
+//check the target path (column 6) of a GAF line
+//a path is either a stable sequence name (e.g. chr1 or chr1:100-200) or oriented segments like >s1<s2>s3
+fn check_gaf_path(path: &str, qname: &str) -> Result<(), String> {
+    if path.is_empty() {
+        return Err(format!("Malformed GAF line for read '{}': empty path", qname));
+    }
+    if path.starts_with('>') || path.starts_with('<') {
+        //every orientation marker must be followed by a segment name
+        if path.split(['>', '<']).skip(1).any(|seg| seg.is_empty()) {
+            return Err(format!("Malformed GAF path '{}' for read '{}': empty segment", path, qname));
+        }
+    } else if path.contains(['>', '<']) {
+        return Err(format!("Malformed GAF path '{}' for read '{}': stable name mixed with oriented segments", path, qname));
+    }
+    Ok(())
+}
+
+//steps of an oriented GAF path as (segment name, reverse), e.g. >s1<s2 gives [("s1", false), ("s2", true)]
+fn path_steps(path: &str) -> Vec<(&str, bool)> {
+    let marks = path.chars().filter(|c| matches!(c, '>' | '<'));
+    marks.zip(path.split(['>', '<']).skip(1)).map(|(mark, name)| (name, mark == '<')).collect()
+}
+
+//a GAF path walks the graph in one direction, so >s1>s2 and <s2<s1 are the same place read from opposite ends
+//the path is taken in the direction whose steps sort first, with the flag set if that is the reverse of the line's own
+//stable names (and PAF targets) are returned as they are
+pub(crate) fn canonical_path(path: &str) -> (Cow<'_, str>, bool) {
+    if !path.starts_with(['>', '<']) {
+        return (Cow::Borrowed(path), false);
+    }
+    let steps = path_steps(path);
+    let reversed: Vec<(&str, bool)> = steps.iter().rev().map(|&(name, rev)| (name, !rev)).collect();
+    if reversed < steps {
+        let path = reversed.iter().map(|&(name, rev)| format!("{}{}", if rev { '<' } else { '>' }, name)).collect();
+        (Cow::Owned(path), true)
+    } else {
+        (Cow::Borrowed(path), false)
+    }
+}
+
+//helper function to get weighted score of split reads using the --score source (AS tag by default)
 //weighted_score = (SUM(score) / SUM(Alignment_len)) * tot read_bps_aligned
-pub fn get_weighted_score(cur_clust : &Vec<String>, tag_prefix: &str) -> Result<(f32, u32), Box<dyn std::error::Error>> {
+//every non-secondary line must carry its score, which aligners may write as integer or float tag (AS:f:)
+pub(crate) fn get_weighted_score(cur_clust : &[String], scorer: &Scorer, gaf: bool) -> Result<(f32, u32), Box<dyn std::error::Error>> {
     let mut sum_alignment_lens = 0;
-    let mut sum_alignment_scores = 0;
+    let mut sum_alignment_scores = 0.0;
     let mut n_splits: u32 = 0;
     let mut read_intervals: Vec<(u32, u32)> = Vec::with_capacity(cur_clust.len());
+    //placement of every segment on the target, only needed for the --chain penalty
+    let mut segments = Vec::new();
 
     //get read length from PAF field 1 (query length) of the first record
     let read_len: u32 = cur_clust[0].split('\t').nth(1)
@@ -217,7 +144,7 @@ pub fn get_weighted_score(cur_clust : &Vec<String>, tag_prefix: &str) -> Result<
         let mut fields = alignment.split('\t');
 
         //skip to relevant columns
-        let _qname = fields.next().ok_or("Malformed PAF line: missing qname field")?;
+        let qname = fields.next().ok_or("Malformed PAF line: missing qname field")?;
         let _qlen = fields.next().ok_or("Malformed PAF line: missing qlen field")?;
         let qstart = fields.next()
             .ok_or("Malformed PAF line: missing qstart field")?
@@ -226,20 +153,21 @@ pub fn get_weighted_score(cur_clust : &Vec<String>, tag_prefix: &str) -> Result<
             .ok_or("Malformed PAF line: missing qend field")?
             .parse::<u32>().map_err(|e| format!("Invalid qend in PAF: {}", e))?;
 
-        let mut is_secondary = false;
-        let mut as_score = 0;
-        //find score and tp tags (start from field 12)
-        for field in fields.skip(8) { // skip to tags
-            if field.starts_with("tp:A:S") { is_secondary = true; } // check for secondary alignment tag
-            if field.starts_with(tag_prefix) {
-                 as_score = field[tag_prefix.len()..].parse()
-                    .map_err(|e| format!("Invalid {} value in PAF: {}", tag_prefix, e))?;
-            }
+        //GAF target is a path through the graph rather than a linear contig name
+        if gaf {
+            let path = alignment.split('\t').nth(5).ok_or("Malformed GAF line: missing path field")?;
+            check_gaf_path(path, qname)?;
         }
 
+        //find tp tag (start from field 12)
+        let is_secondary = fields.skip(8).any(|field| field.starts_with("tp:A:S")); // check for secondary alignment tag
+
         //do not factor secondary alignments into choosing best alignment
         if is_secondary { continue; }
 
+        //throw error if the score (or what it is derived from) is missing
+        let as_score = scorer.line(alignment)?;
+
         n_splits += 1;
 
         //should not happen if paf is formatted correctly
@@ -255,6 +183,9 @@ pub fn get_weighted_score(cur_clust : &Vec<String>, tag_prefix: &str) -> Result<
 
         sum_alignment_scores += as_score;
 
+        if scorer.chain() {
+            segments.push(line_segment(alignment)?);
+        }
     }
 
     if sum_alignment_lens <= 0 {
@@ -267,34 +198,145 @@ pub fn get_weighted_score(cur_clust : &Vec<String>, tag_prefix: &str) -> Result<
 
     //weighted_score = (SUM(Alignment_Score) / SUM(Alignment_len)) * tot read_bps_aligned * cov_fraction
     let cov_fraction = read_bps_aligned as f32 / read_len as f32;
-    return Ok(((sum_alignment_scores as f32 / sum_alignment_lens as f32) * read_bps_aligned as f32 * cov_fraction, n_splits));
+    //minus the penalty of split alignments that are no collinear chain (--chain)
+    let chain_penalty = scorer.chain_penalty(&mut segments) as f32;
+    return Ok(((sum_alignment_scores as f32 / sum_alignment_lens as f32) * read_bps_aligned as f32 * cov_fraction - chain_penalty, n_splits));
 
 }
 
-pub fn compare_clusters<'a>(clust1:&'a Vec<String>, clust2:&'a Vec<String>, args: &Cli) ->  Result<(crate::Winner, Option<u8>), Box<dyn std::error::Error>> {
+//placement of a PAF/GAF line on the read and the target
+//a GAF path counts as one target, taken in canonical orientation (see canonical_path) with strand and coordinates flipped to match
+fn line_segment(line: &str) -> Result<Segment<Cow<'_, str>>, String> {
+    let fields: Vec<&str> = line.split('\t').take(9).collect();
+    if fields.len() < 9 {
+        return Err(format!("Malformed PAF line for read '{}': fewer than 9 fields", line_qname(line)));
+    }
+    let num = |i: usize| fields[i].parse::<i64>().map_err(|e| format!("Invalid coordinate '{}' in PAF line of read '{}': {}", fields[i], fields[0], e));
+    let (target, flip) = canonical_path(fields[5]);
+    let (tstart, tend) = if flip {
+        let len = num(6)?;
+        (len - num(8)?, len - num(7)?)
+    } else {
+        (num(7)?, num(8)?)
+    };
+    Ok(Segment {
+        qstart: num(2)? as u32,
+        qend: num(3)? as u32,
+        target,
+        reverse: (fields[4] == "-") != flip,
+        tstart,
+        tend,
+    })
+}
 
-    match (clust1[0].split('\t').nth(5), clust2[0].split('\t').nth(5)) {
-        (Some("*"), Some("*")) => {return Ok((crate::Winner::Unmapped, None));}, // both reads unmapped
-        (Some("*"), _) => return Ok((crate::Winner::Asm2, if args.no_hapq { None } else { Some(60u8) })), // asm1 hap unmapped
-        (_, Some("*")) => return Ok((crate::Winner::Asm1, if args.no_hapq { None } else { Some(60u8) })), // asm2 hap unmapped
-        _ => {} // continue if mapped to both haps
+//HapQ cap from how uniquely a read is placed within one input, as sam::placement_cap:
+//MAPQ (column 12), s1/s2, AS/XS and the score of the best line against the best secondary line (tp:A:S)
+pub(crate) fn placement_cap(cluster: &[String], scorer: &Scorer, args: &Cli) -> Option<u8> {
+    let (signals, match_sc, model) = (&args.hapq_multimap, args.match_sc, &args.model);
+    let mut cap: Option<u8> = None;
+    let mut lower = |q: u8| cap = Some(cap.map_or(q, |c| c.min(q)));
+    let is_secondary = |line: &str| line.split('\t').skip(12).any(|f| f.starts_with("tp:A:S"));
+    let score_tag = |line: &str, prefix: &str| line.split('\t').skip(12).find_map(|f| f.strip_prefix(prefix)).and_then(|v| v.parse::<f32>().ok());
+    let best = |secondary: bool| cluster.iter()
+        .filter(|l| is_secondary(l) == secondary)
+        .filter_map(|l| scorer.line(l).ok())
+        .fold(None, |best: Option<f64>, s| Some(best.map_or(s, |b| b.max(s))));
+    //PAF has no supplementary flag, so the best non-secondary line stands for the primary
+    if signals.contains(&MultimapSignal::Secondary) {
+        if let (Some(primary), Some(secondary)) = (best(false), best(true)) {
+            lower(crate::compute_hapq(primary as f32, secondary as f32, 1, match_sc, model));
+        }
+    }
+    for line in cluster.iter().filter(|l| !is_secondary(l)) {
+        if signals.contains(&MultimapSignal::Mapq) {
+            if let Some(mapq) = line.split('\t').nth(11).and_then(|q| q.parse::<u32>().ok()).filter(|&q| q != 255) {
+                lower(mapq.min(60) as u8);
+            }
+        }
+        if signals.contains(&MultimapSignal::S2) {
+            if let (Some(s1), Some(s2)) = (score_tag(line, "s1:i:"), score_tag(line, "s2:i:")) {
+                lower(crate::compute_hapq(s1, s2, 1, 1.0, model));
+            }
+        }
+        if signals.contains(&MultimapSignal::Xs) {
+            if let (Some(a), Some(xs)) = (score_tag(line, "AS:i:"), score_tag(line, "XS:i:")) {
+                lower(crate::compute_hapq(a, xs, 1, match_sc, model));
+            }
+        }
     }
+    cap
+}
 
-    let tag_prefix = if args.ms { "ms:i:" } else { "AS:i:" };
-    //get score and number of non-secondary alignment segments for each cluster
-    let (score1, n_splits1) = get_weighted_score(clust1, tag_prefix)?;
-    let (score2, n_splits2) = get_weighted_score(clust2, tag_prefix)?;
-
-    //return respective winner depending on which AS is higher,
-    //both is a special case that can be determined by user input
-    if score1 > score2 {
-        let hapq = if args.no_hapq { None } else { Some(crate::compute_hapq(score1, score2, n_splits1, args.match_sc)) };
-        Ok((crate::Winner::Asm1, hapq))
-    } else if score1 < score2 {
-        let hapq = if args.no_hapq { None } else { Some(crate::compute_hapq(score2, score1, n_splits2, args.match_sc)) };
-        Ok((crate::Winner::Asm2, hapq))
-    } else {
-        let hapq = if args.no_hapq { None } else { Some(0u8) };
-        Ok((crate::Winner::Both, hapq))
+//chain of the non-secondary lines of a read as hc tag value, as sam::chains; None if it is aligned in one piece
+pub(crate) fn chain(cluster: &[String]) -> Result<Option<String>, String> {
+    let mut segments = Vec::new();
+    for line in cluster.iter().filter(|l| !l.split('\t').skip(12).any(|f| f.starts_with("tp:A:S"))) {
+        segments.push(line_segment(line)?);
+    }
+    if segments.len() < 2 {
+        return Ok(None);
+    }
+    Ok(Some(crate::chain_junctions(&mut segments).iter().map(|j| j.code()).collect()))
+}
+
+//read name of a PAF/GAF line
+pub(crate) fn line_qname(line: &str) -> &str {
+    line.split('\t').next().unwrap_or("")
+}
+
+//length of a read for HapQ calibration, from the query length of its first line
+pub(crate) fn read_length(cluster: &[String]) -> u32 {
+    cluster[0].split('\t').nth(1).and_then(|l| l.parse().ok()).unwrap_or(0)
+}
+
+//stats of the non-secondary lines of a read that break ties in score, as sam::tie_stats:
+//MAPQ (column 12), NM:i: tag, query span (qend - qstart) and target start (of a GAF path in canonical orientation)
+pub(crate) fn tie_stats(cluster: &[String], splits: u32) -> TieStats {
+    let lines: Vec<Vec<&str>> = cluster.iter()
+        .map(|l| l.split('\t').collect::<Vec<&str>>())
+        .filter(|f| !f.iter().skip(12).any(|t| t.starts_with("tp:A:S")))
+        .collect();
+    let mapq = lines.iter().filter_map(|f| f.get(11).and_then(|q| q.parse::<u8>().ok())).filter(|&q| q != 255).min();
+    let nm = lines.iter().map(|f| f.iter().skip(12).find_map(|t| t.strip_prefix("NM:i:")).and_then(|v| v.parse::<i64>().ok())).sum();
+    let span = lines.iter().map(|f| {
+        let num = |i: usize| f.get(i).and_then(|v| v.parse::<u64>().ok()).unwrap_or(0);
+        num(3).saturating_sub(num(2))
+    }).sum();
+    //PAF has no supplementary flag, the first non-secondary line stands for the primary
+    let locus = cluster.iter().find(|l| !l.split('\t').skip(12).any(|t| t.starts_with("tp:A:S")))
+        .and_then(|l| line_segment(l).ok())
+        .map(|s| (s.target.as_bytes().to_vec(), s.tstart));
+    TieStats { splits, mapq, nm, span, locus }
+}
+
+//read group (RG:Z: tag) of a read, from the first of its lines that has one
+pub(crate) fn read_group(cluster: &[String]) -> Option<String> {
+    cluster.iter().find_map(|line| line.split('\t').skip(12).find_map(|f| f.strip_prefix("RG:Z:")).map(|rg| rg.to_string()))
+}
+
+//a cluster is unmapped if its target name (GAF path) is '*'
+pub(crate) fn is_unmapped(cluster: &[String]) -> bool {
+    cluster[0].split('\t').nth(5) == Some("*")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gaf_paths_in_either_direction_match() {
+        assert_eq!(canonical_path(">s1>s2"), (Cow::Borrowed(">s1>s2"), false));
+        assert_eq!(canonical_path("<s2<s1"), (Cow::Owned(">s1>s2".to_string()), true));
+        assert_eq!(canonical_path("<s1"), (Cow::Owned(">s1".to_string()), true));
+        //segment names sort as names, not by their orientation marks
+        assert_eq!(canonical_path(">s2<s1").0, canonical_path(">s1<s2").0);
+        assert_eq!(canonical_path("chr1:100-200"), (Cow::Borrowed("chr1:100-200"), false));
+    }
+
+    #[test]
+    fn reversed_gaf_path_flips_strand_and_coordinates() {
+        let forward = line_segment("r1\t1000\t0\t500\t+\t>s1>s2\t2000\t100\t600\t500\t500\t60").unwrap();
+        let reversed = line_segment("r1\t1000\t0\t500\t-\t<s2<s1\t2000\t1400\t1900\t500\t500\t60").unwrap();
+        assert_eq!((forward.target, forward.reverse, forward.tstart, forward.tend), (reversed.target, reversed.reverse, reversed.tstart, reversed.tend));
     }
 }
@@ -0,0 +1,513 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use rust_htslib::bam::HeaderView;
+use rust_htslib::htslib;
+
+use crate::open_text;
+
+//CRAM stores reads as differences to their reference, so every @SQ contig of a CRAM header must be found:
+//in an explicit FASTA (--refs/--out-refs), in a FASTA of --ref-dir matched by name and M5 checksum,
+//or by htslib itself, from REF_CACHE/REF_PATH by the @SQ M5 checksum or from the @SQ UR path
+
+//bases per line of combined references
+const FASTA_WIDTH: usize = 60;
+
+//one @SQ line of a header
+struct SqLine {
+    name: String,
+    len: u64,
+    m5: Option<String>,
+    ur: Option<String>,
+}
+
+fn sq_lines(header: &HeaderView) -> Vec<SqLine> {
+    let text = String::from_utf8_lossy(header.as_bytes()).into_owned();
+    text.lines().filter(|l| l.starts_with("@SQ\t")).map(|line| {
+        let mut sq = SqLine { name: String::new(), len: 0, m5: None, ur: None };
+        for field in line.split('\t').skip(1) {
+            match field.split_at(field.len().min(3)) {
+                ("SN:", v) => sq.name = v.to_string(),
+                ("LN:", v) => sq.len = v.parse().unwrap_or(0),
+                ("M5:", v) => sq.m5 = Some(v.to_ascii_lowercase()),
+                ("UR:", v) => sq.ur = Some(v.to_string()),
+                _ => {}
+            }
+        }
+        sq
+    }).collect()
+}
+
+//streaming MD5 through htslib, as the SAM spec defines M5: upper case, without whitespace
+struct Md5(*mut htslib::hts_md5_context);
+
+impl Md5 {
+    fn new() -> Result<Self, String> {
+        let ctx = unsafe { htslib::hts_md5_init() };
+        if ctx.is_null() {
+            return Err("failed to initialise MD5".into());
+        }
+        Ok(Md5(ctx))
+    }
+
+    fn update(&mut self, bases: &[u8]) {
+        unsafe { htslib::hts_md5_update(self.0, bases.as_ptr() as *const _, bases.len() as _) };
+    }
+
+    fn hex(self) -> String {
+        let mut digest = [0u8; 16];
+        let mut hex = [0 as std::os::raw::c_char; 33];
+        unsafe {
+            htslib::hts_md5_final(digest.as_mut_ptr(), self.0);
+            htslib::hts_md5_hex(hex.as_mut_ptr(), digest.as_ptr());
+        }
+        hex[..32].iter().map(|&c| c as u8 as char).collect()
+    }
+}
+
+impl Drop for Md5 {
+    fn drop(&mut self) {
+        unsafe { htslib::hts_md5_destroy(self.0) };
+    }
+}
+
+//random access to the sequences of an indexed FASTA through htslib faidx (the .fai is built if missing)
+pub(crate) struct Fasta(*mut htslib::faidx_t);
+
+impl Fasta {
+    pub(crate) fn open(path: &str) -> Result<Self, String> {
+        let c_path = CString::new(path).map_err(|_| format!("Invalid FASTA path '{}'", path))?;
+        let fai = unsafe { htslib::fai_load(c_path.as_ptr()) };
+        if fai.is_null() {
+            return Err(format!("Failed to open FASTA '{}' (plain or bgzip-compressed, with a .fai that can be built next to it)", path));
+        }
+        Ok(Fasta(fai))
+    }
+
+    //upper-case bases start..end (0-based, end exclusive) of a contig
+    pub(crate) fn fetch(&self, name: &CStr, start: i64, end: i64) -> Result<Vec<u8>, String> {
+        let mut len: htslib::hts_pos_t = 0;
+        let seq = unsafe { htslib::faidx_fetch_seq64(self.0, name.as_ptr(), start, end - 1, &mut len) };
+        if seq.is_null() || len < 0 {
+            return Err(format!("Failed to fetch {}:{}-{} from the FASTA", name.to_string_lossy(), start + 1, end));
+        }
+        //htslib allocated the sequence, so it is copied and handed back to htslib to free
+        let mut bases = unsafe { std::slice::from_raw_parts(seq as *const u8, len as usize) }.to_vec();
+        unsafe { htslib::hts_free(seq as *mut _) };
+        bases.make_ascii_uppercase();
+        Ok(bases)
+    }
+}
+
+impl Drop for Fasta {
+    fn drop(&mut self) {
+        unsafe { htslib::fai_destroy(self.0) };
+    }
+}
+
+//a sequence of a FASTA file, md5 only if it was hashed
+struct FastaContig {
+    name: String,
+    len: u64,
+    md5: Option<String>,
+}
+
+//names and lengths of the sequences of a FASTA, from its .fai if there is one and no checksums are needed,
+//else by reading the whole file (gzip/BGZF is decompressed)
+fn fasta_contigs(path: &str, hash: bool) -> Result<Vec<FastaContig>, String> {
+    if !hash {
+        if let Ok(fai) = std::fs::read_to_string(format!("{}.fai", path)) {
+            return Ok(fai.lines().filter_map(|line| {
+                let mut fields = line.split('\t');
+                let name = fields.next()?.to_string();
+                let len = fields.next()?.parse().ok()?;
+                Some(FastaContig { name, len, md5: None })
+            }).collect());
+        }
+    }
+    let reader = open_text(path).map_err(|e| format!("Failed to read reference '{}': {}", path, e))?;
+    let mut contigs: Vec<FastaContig> = Vec::new();
+    let mut md5: Option<Md5> = None;
+    let mut bases: Vec<u8> = Vec::new();
+    for line in reader.split(b'\n') {
+        let line = line.map_err(|e| format!("Failed to read reference '{}': {}", path, e))?;
+        if let Some(header) = line.strip_prefix(b">") {
+            if let (Some(last), Some(m)) = (contigs.last_mut(), md5.take()) {
+                last.md5 = Some(m.hex());
+            }
+            let name = String::from_utf8_lossy(header).split_whitespace().next().unwrap_or("").to_string();
+            contigs.push(FastaContig { name, len: 0, md5: None });
+            if hash {
+                md5 = Some(Md5::new()?);
+            }
+            continue;
+        }
+        let last = contigs.last_mut().ok_or_else(|| format!("Reference '{}' is not a FASTA file", path))?;
+        bases.clear();
+        bases.extend(line.iter().filter(|b| (33..=126).contains(*b)).map(|b| b.to_ascii_uppercase()));
+        last.len += bases.len() as u64;
+        if let Some(m) = md5.as_mut() {
+            m.update(&bases);
+        }
+    }
+    if let (Some(last), Some(m)) = (contigs.last_mut(), md5.take()) {
+        last.md5 = Some(m.hex());
+    }
+    Ok(contigs)
+}
+
+//REF_CACHE and REF_PATH entries, split like htslib does (':' separated, but URLs keep their '://')
+fn ref_env(var: &str) -> Vec<String> {
+    let value = std::env::var(var).unwrap_or_default();
+    let mut entries: Vec<String> = Vec::new();
+    for piece in value.split(':') {
+        match entries.last_mut() {
+            Some(last) if piece.starts_with("//") => {
+                last.push(':');
+                last.push_str(piece);
+            }
+            _ => entries.push(piece.to_string()),
+        }
+    }
+    entries.retain(|e| !e.is_empty());
+    entries
+}
+
+//htslib's cache when neither REF_CACHE nor REF_PATH is set
+fn default_cache() -> String {
+    let base = std::env::var("XDG_CACHE_HOME").ok().filter(|v| !v.is_empty())
+        .or_else(|| std::env::var("HOME").ok().filter(|v| !v.is_empty()).map(|h| format!("{}/.cache", h)))
+        .unwrap_or_else(|| "/tmp".to_string());
+    format!("{}/hts-ref/%2s/%2s/%s", base)
+}
+
+fn is_url(entry: &str) -> bool {
+    entry.starts_with("URL=") || entry.contains("://")
+}
+
+//path of an M5 checksum in a REF_CACHE/REF_PATH entry: %Ns takes the next N characters, %s the rest,
+//and whatever is left over is appended as /rest
+fn expand_md5_path(template: &str, md5: &str) -> String {
+    let mut path = String::new();
+    let mut rest = md5;
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            path.push(c);
+            continue;
+        }
+        let mut digits = String::new();
+        while let Some(d) = chars.peek().filter(|d| d.is_ascii_digit()) {
+            digits.push(*d);
+            chars.next();
+        }
+        if chars.peek() == Some(&'s') {
+            chars.next();
+            let n = digits.parse().unwrap_or(rest.len()).min(rest.len());
+            path.push_str(&rest[..n]);
+            rest = &rest[n..];
+        } else {
+            path.push('%');
+            path.push_str(&digits);
+        }
+    }
+    if !rest.is_empty() {
+        path = format!("{}/{}", path.trim_end_matches('/'), rest);
+    }
+    path
+}
+
+//why htslib cannot find a contig by itself, None if it can:
+//its M5 is in a local REF_CACHE/REF_PATH entry (or REF_PATH names a server), or its UR is an existing file
+fn unresolved_reason(sq: &SqLine, cache: &[String], path: &[String]) -> Option<String> {
+    let m5_reason = match &sq.m5 {
+        Some(m5) => {
+            if cache.iter().chain(path.iter().filter(|e| !is_url(e))).any(|e| Path::new(&expand_md5_path(e, m5)).is_file()) {
+                return None;
+            }
+            if path.iter().any(|e| is_url(e)) {
+                return None;
+            }
+            format!("M5 {} not in REF_CACHE/REF_PATH", m5)
+        }
+        None => "no M5".to_string(),
+    };
+    let ur_reason = match &sq.ur {
+        Some(ur) => {
+            if Path::new(ur.strip_prefix("file:").unwrap_or(ur)).is_file() {
+                return None;
+            }
+            format!("UR {} not found", ur)
+        }
+        None => "no UR".to_string(),
+    };
+    Some(format!("{} ({}, {})", sq.name, m5_reason, ur_reason))
+}
+
+//a FASTA of --ref-dir, hashed the first time a header with M5 checksums is matched against it
+struct DirFasta {
+    path: String,
+    contigs: Vec<FastaContig>,
+    hashed: bool,
+}
+
+//result of looking up the contigs of a header in --ref-dir
+enum DirMatch {
+    //a FASTA holding every contig, one of the directory or combined from several of its FASTAs
+    Fasta(String),
+    //names of the contigs no FASTA of the directory holds
+    Missing(Vec<String>),
+}
+
+//finds the reference of every CRAM input and output, scanning --ref-dir once for all of them
+//references combined from several FASTAs of --ref-dir are written to tmp_dir and removed on drop
+pub(crate) struct RefResolver {
+    dir: Option<String>,
+    tmp_dir: PathBuf,
+    fastas: Option<Vec<DirFasta>>,
+    //combined FASTA of every set of (header contig, FASTA, FASTA contig) already built
+    combined: HashMap<Vec<(String, usize, String)>, String>,
+}
+
+impl RefResolver {
+    pub(crate) fn new(ref_dir: Option<&str>, tmp_dir: &Path) -> Self {
+        RefResolver { dir: ref_dir.map(|d| d.to_string()), tmp_dir: tmp_dir.to_path_buf(), fastas: None, combined: HashMap::new() }
+    }
+
+    //FASTA to set on a CRAM reader/writer with this header, None if htslib finds every contig itself
+    //an explicit FASTA must hold every contig with its length, otherwise --ref-dir is searched,
+    //then REF_CACHE/REF_PATH and UR; contigs that cannot be found are listed in the error
+    pub(crate) fn resolve(&mut self, header: &HeaderView, explicit: Option<&str>, what: &str) -> Result<Option<String>, String> {
+        let sqs = sq_lines(header);
+        if let Some(fasta) = explicit {
+            check_fasta(fasta, &sqs, what)?;
+            return Ok(Some(fasta.to_string()));
+        }
+        let not_in_dir = match self.find_in_dir(&sqs)? {
+            Some(DirMatch::Fasta(fasta)) => return Ok(Some(fasta)),
+            Some(DirMatch::Missing(names)) => Some(names),
+            None => None,
+        };
+        let (mut cache, path) = (ref_env("REF_CACHE"), ref_env("REF_PATH"));
+        if cache.is_empty() && path.is_empty() {
+            //without either, htslib only looks in its default cache (before downloading from the EBI, which is not relied on here)
+            cache.push(default_cache());
+        }
+        let mut unresolved: Vec<(&SqLine, String)> = sqs.iter().filter_map(|sq| unresolved_reason(sq, &cache, &path).map(|r| (sq, r))).collect();
+        if unresolved.is_empty() {
+            return Ok(None);
+        }
+        if let (Some(dir), Some(not_in_dir)) = (&self.dir, not_in_dir) {
+            //contigs of --ref-dir cannot be combined with those htslib finds elsewhere
+            unresolved.retain(|(sq, _)| not_in_dir.contains(&sq.name));
+            if unresolved.is_empty() {
+                return Err(format!("The contigs of {} are split between --ref-dir and REF_CACHE/REF_PATH/UR: {} contig(s) are not in any FASTA of {} ({}), but others are only there; put every contig in one of them",
+                    what, not_in_dir.len(), dir, not_in_dir.join(", ")));
+            }
+            for (_, reason) in unresolved.iter_mut() {
+                reason.insert_str(reason.len() - 1, &format!(", not in any FASTA of {}", dir));
+            }
+        }
+        Err(format!("Cannot find the reference of {} contig(s) of {}: {}; give the FASTA with --refs (--out-refs for output), a directory of FASTAs with --ref-dir, or set REF_PATH/REF_CACHE",
+            unresolved.len(), what, unresolved.iter().map(|(_, r)| r.as_str()).collect::<Vec<&str>>().join(", ")))
+    }
+
+    //FASTA holding the sequences of every contig of a header, to read reference bases from:
+    //the explicit FASTA, else one of --ref-dir (or combined from several), else the file named by the @SQ UR fields
+    //if it holds every contig (REF_CACHE/REF_PATH entries are no FASTAs, so they cannot be used here)
+    pub(crate) fn fasta(&mut self, header: &HeaderView, explicit: Option<&str>, what: &str) -> Result<String, String> {
+        let sqs = sq_lines(header);
+        if let Some(fasta) = explicit {
+            check_fasta(fasta, &sqs, what)?;
+            return Ok(fasta.to_string());
+        }
+        let not_in_dir = match self.find_in_dir(&sqs)? {
+            Some(DirMatch::Fasta(fasta)) => return Ok(fasta),
+            Some(DirMatch::Missing(names)) => Some(names),
+            None => None,
+        };
+        let urs: Vec<Option<&str>> = sqs.iter().map(|sq| sq.ur.as_deref().map(|ur| ur.strip_prefix("file:").unwrap_or(ur))).collect();
+        if let Some(Some(ur)) = urs.first().copied() {
+            if urs.iter().all(|u| *u == Some(ur)) && Path::new(ur).is_file() && check_fasta(ur, &sqs, what).is_ok() {
+                return Ok(ur.to_string());
+            }
+        }
+        match (&self.dir, not_in_dir) {
+            (Some(dir), Some(names)) => Err(format!("Cannot find the reference FASTA of {}: {} contig(s) are not in any FASTA of {} ({}); give it with --refs",
+                what, names.len(), dir, names.join(", "))),
+            _ => Err(format!("Cannot find the reference FASTA of {}; give it with --refs or a directory of FASTAs with --ref-dir", what)),
+        }
+    }
+
+    //reference of a header in --ref-dir, None without --ref-dir:
+    //the first FASTA holding every contig by name and length (and by M5 where the header has one),
+    //else every contig is looked up on its own, by M5 if the header has one and else by name and length,
+    //and a FASTA combining them is built if they are all found
+    fn find_in_dir(&mut self, sqs: &[SqLine]) -> Result<Option<DirMatch>, String> {
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => return Ok(None),
+        };
+        if self.fastas.is_none() {
+            self.fastas = Some(scan_dir(&dir)?);
+        }
+        let with_m5 = sqs.iter().any(|sq| sq.m5.is_some());
+        for fasta in self.fastas.as_mut().unwrap().iter_mut() {
+            let covers = |contigs: &[FastaContig], check_md5: bool| {
+                let by_name: HashMap<&str, &FastaContig> = contigs.iter().map(|c| (c.name.as_str(), c)).collect();
+                sqs.iter().all(|sq| match by_name.get(sq.name.as_str()) {
+                    Some(c) => c.len == sq.len && (!check_md5 || sq.m5.is_none() || c.md5 == sq.m5),
+                    None => false,
+                })
+            };
+            if !covers(&fasta.contigs, false) {
+                continue;
+            }
+            if with_m5 && !fasta.hashed {
+                fasta.contigs = fasta_contigs(&fasta.path, true)?;
+                fasta.hashed = true;
+            }
+            if covers(&fasta.contigs, true) {
+                return Ok(Some(DirMatch::Fasta(fasta.path.clone())));
+            }
+        }
+
+        //contigs spread over several FASTAs, or under other names
+        let fastas = self.fastas.as_mut().unwrap();
+        if with_m5 {
+            for fasta in fastas.iter_mut().filter(|f| !f.hashed) {
+                fasta.contigs = fasta_contigs(&fasta.path, true)?;
+                fasta.hashed = true;
+            }
+        }
+        //first FASTA and contig of every checksum and of every name and length
+        let mut by_md5: HashMap<&str, (usize, &str)> = HashMap::new();
+        let mut by_name: HashMap<(&str, u64), (usize, &str)> = HashMap::new();
+        for (i, fasta) in fastas.iter().enumerate() {
+            for c in fasta.contigs.iter() {
+                if let Some(md5) = &c.md5 {
+                    by_md5.entry(md5.as_str()).or_insert((i, c.name.as_str()));
+                }
+                by_name.entry((c.name.as_str(), c.len)).or_insert((i, c.name.as_str()));
+            }
+        }
+        let mut sources: Vec<(String, usize, String)> = Vec::with_capacity(sqs.len());
+        let mut missing: Vec<String> = Vec::new();
+        for sq in sqs.iter() {
+            let found = match &sq.m5 {
+                Some(m5) => by_md5.get(m5.as_str()),
+                None => by_name.get(&(sq.name.as_str(), sq.len)),
+            };
+            match found {
+                Some(&(i, name)) => sources.push((sq.name.clone(), i, name.to_string())),
+                None => missing.push(sq.name.clone()),
+            }
+        }
+        if !missing.is_empty() {
+            return Ok(Some(DirMatch::Missing(missing)));
+        }
+        sources.sort();
+        if let Some(path) = self.combined.get(&sources) {
+            return Ok(Some(DirMatch::Fasta(path.clone())));
+        }
+        let path = self.combine(&sources)?;
+        self.combined.insert(sources, path.clone());
+        Ok(Some(DirMatch::Fasta(path)))
+    }
+
+    //write a FASTA in tmp_dir holding every header contig, copied from the contig of the --ref-dir FASTA it was found in
+    fn combine(&self, sources: &[(String, usize, String)]) -> Result<String, String> {
+        let fastas = self.fastas.as_ref().unwrap();
+        let path = self.tmp_dir.join(format!("diplinator.{}.{}.ref.fa", std::process::id(), self.combined.len()));
+        let display = path.display().to_string();
+        let file = std::fs::File::create(&path).map_err(|e| format!("Failed to create combined reference '{}': {}", display, e))?;
+        let mut out = std::io::BufWriter::new(file);
+        let write_err = |e: std::io::Error| format!("Failed to write combined reference '{}': {}", display, e);
+        let mut files: Vec<usize> = sources.iter().map(|(_, i, _)| *i).collect();
+        files.sort();
+        files.dedup();
+        eprintln!("Combining the reference of {} contig(s) from {} FASTA(s) of --ref-dir into '{}'", sources.len(), files.len(), display);
+        for i in files {
+            //header contigs of every contig of this FASTA, usually one
+            let mut wanted: HashMap<&str, Vec<&str>> = HashMap::new();
+            for (name, _, contig) in sources.iter().filter(|(_, j, _)| *j == i) {
+                wanted.entry(contig.as_str()).or_default().push(name.as_str());
+            }
+            let reader = open_text(&fastas[i].path).map_err(|e| format!("Failed to read reference '{}': {}", fastas[i].path, e))?;
+            let mut current: Option<Vec<&str>> = None;
+            let mut seq: Vec<u8> = Vec::new();
+            let mut flush = |names: Option<Vec<&str>>, seq: &mut Vec<u8>| -> Result<(), String> {
+                for name in names.into_iter().flatten() {
+                    writeln!(out, ">{}", name).map_err(write_err)?;
+                    for line in seq.chunks(FASTA_WIDTH) {
+                        out.write_all(line).and_then(|_| out.write_all(b"\n")).map_err(write_err)?;
+                    }
+                }
+                seq.clear();
+                Ok(())
+            };
+            for line in reader.split(b'\n') {
+                let line = line.map_err(|e| format!("Failed to read reference '{}': {}", fastas[i].path, e))?;
+                if let Some(header) = line.strip_prefix(b">") {
+                    flush(current.take(), &mut seq)?;
+                    let name = String::from_utf8_lossy(header).split_whitespace().next().unwrap_or("").to_string();
+                    current = wanted.get(name.as_str()).cloned();
+                } else if current.is_some() {
+                    seq.extend(line.iter().filter(|b| (33..=126).contains(*b)).map(|b| b.to_ascii_uppercase()));
+                }
+            }
+            flush(current.take(), &mut seq)?;
+        }
+        out.flush().map_err(write_err)?;
+        Ok(display)
+    }
+}
+
+impl Drop for RefResolver {
+    //combined references are only needed while the CRAM files are read and written
+    fn drop(&mut self) {
+        for path in self.combined.values() {
+            let _ = std::fs::remove_file(path);
+            let _ = std::fs::remove_file(format!("{}.fai", path));
+        }
+    }
+}
+
+//check that a FASTA holds every contig of a header with its length
+fn check_fasta(fasta: &str, sqs: &[SqLine], what: &str) -> Result<(), String> {
+    let contigs = fasta_contigs(fasta, false)?;
+    let lens: HashMap<&str, u64> = contigs.iter().map(|c| (c.name.as_str(), c.len)).collect();
+    let missing: Vec<String> = sqs.iter().filter_map(|sq| match lens.get(sq.name.as_str()) {
+        None => Some(format!("{} (not in FASTA)", sq.name)),
+        Some(&len) if len != sq.len => Some(format!("{} (length {} in FASTA, {} in header)", sq.name, len, sq.len)),
+        Some(_) => None,
+    }).collect();
+    if !missing.is_empty() {
+        return Err(format!("Reference '{}' of {} does not match {} contig(s): {}", fasta, what, missing.len(), missing.join(", ")));
+    }
+    Ok(())
+}
+
+//every FASTA of a directory (.fa/.fasta/.fna, optionally .gz), in name order
+fn scan_dir(dir: &str) -> Result<Vec<DirFasta>, String> {
+    let entries = std::fs::read_dir(dir).map_err(|e| format!("Failed to read --ref-dir '{}': {}", dir, e))?;
+    let mut paths: Vec<String> = entries.flatten()
+        .map(|e| e.path())
+        .filter(|p| p.is_file())
+        .map(|p| p.to_string_lossy().into_owned())
+        .filter(|p| {
+            let name = p.strip_suffix(".gz").unwrap_or(p);
+            [".fa", ".fasta", ".fna"].iter().any(|ext| name.ends_with(ext))
+        })
+        .collect();
+    paths.sort();
+    if paths.is_empty() {
+        eprintln!("Warning: --ref-dir '{}' holds no FASTA files (.fa/.fasta/.fna)", dir);
+    }
+    paths.into_iter().map(|path| {
+        let contigs = fasta_contigs(&path, false)?;
+        Ok(DirFasta { path, contigs, hashed: false })
+    }).collect()
+}
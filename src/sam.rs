@@ -1,268 +1,389 @@
-use std::cmp::max;
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
 use std::ffi::CString;
 use std::iter::Peekable;
-use std::path::Path;
+use std::os::raw::{c_char, c_int};
 
 
 use rust_htslib::{
-    bam::{self, record::Aux, record::Cigar, Read, Record, Writer},
+    bam::{self, index::Type as IndexType, record::Aux, record::Cigar, Read, Record},
     errors::Error as BamError,
     htslib,
 };
 
-use crate::cli::Cli;
+use crate::cli::{Cli, MultimapSignal, NameOrder};
+use crate::score::Scorer;
+use crate::Segment;
+use crate::sort::{coordinate_cmp, RecordSorter};
+use crate::ties::TieStats;
 
+//stream of records of one input, either read directly or regrouped by read name
+pub(crate) type RecordStream<'a> = Box<dyn Iterator<Item = Result<Record, BamError>> + 'a>;
 
-/// Helper function to peek at the file format using c path
-fn get_format_from_path<P: AsRef<Path>>(path: P) -> Result<bam::Format, Box<dyn std::error::Error>> {
-    let path_str = path.as_ref().to_str().ok_or("Invalid UTF-8 path")?;
-    let c_path = CString::new(path_str)
-        .map_err(|_| format!("Invalid path (contains null byte): {}", path_str))?;
 
-    unsafe {
-        let hts_file = htslib::hts_open(c_path.as_ptr(), b"r\0".as_ptr() as *const i8);
-        if hts_file.is_null() {
-            return Err(format!("Could not open file: {}", path_str).into());
-        }
-        let format_struct = (*hts_file).format;
-        htslib::hts_close(hts_file);
+/// Helper function to get the file format htslib detected when opening the file
+/// the stream is only opened once, so this also works on pipes and stdin
+pub(crate) fn get_format(reader: &bam::Reader) -> Result<bam::Format, Box<dyn std::error::Error>> {
+    let format_struct = unsafe { (*reader.htsfile()).format };
 
-        // Map the C format to the Rust enum
-        match format_struct.format {
-            htslib::htsExactFormat_bam => Ok(bam::Format::Bam),
-            htslib::htsExactFormat_cram => Ok(bam::Format::Cram),
-            htslib::htsExactFormat_sam => Ok(bam::Format::Sam),
-            _ => Err(format!("Unsupported or unknown file format for: {}", path_str).into()),
-        }
+    // Map the C format to the Rust enum
+    match format_struct.format {
+        htslib::htsExactFormat_bam => Ok(bam::Format::Bam),
+        htslib::htsExactFormat_cram => Ok(bam::Format::Cram),
+        htslib::htsExactFormat_sam => Ok(bam::Format::Sam),
+        _ => Err("Unsupported or unknown file format".into()),
     }
+}
 
+//open an input by path, '-' reads from stdin
+pub(crate) fn open_reader(path: &str) -> Result<bam::Reader, BamError> {
+    if path == "-" {
+        bam::Reader::from_stdin()
+    } else {
+        bam::Reader::from_path(path)
+    }
 }
 
+//SAM/BAM/CRAM output file
+//rust-htslib's Writer writes the header as soon as it is created, before the reference, CRAM version or
+//compression level can be set (a CRAM without a reference at that point embeds the reference sequence),
+//so the file is opened through htslib and set up before the header is written
+//with --sort-output, records are collected in a memory-bounded sort and written and indexed by finish()
+pub(crate) struct SamWriter {
+    f: *mut htslib::htsFile,
+    header: bam::HeaderView,
+    path: String,
+    format: bam::Format,
+    sorter: Option<Box<RecordSorter>>,
+    //index type of sorted output, None if it is not indexed
+    index: Option<IndexType>,
+    n_threads: usize,
+}
 
-fn formats_equal(a: &bam::Format, b: &bam::Format) -> bool {
-    match (a, b) {
-        (bam::Format::Bam, bam::Format::Bam) => true,
-        (bam::Format::Cram, bam::Format::Cram) => true,
-        (bam::Format::Sam, bam::Format::Sam) => true,
-        _ => false,
+impl SamWriter {
+    //create an output ('-' for stdout) with the --level/--cram-version/--embed-ref/--sort-output settings of args
+    //reference is the FASTA CRAM output is written against, it is not used for other formats
+    pub(crate) fn create(path: &str, header: &bam::Header, format: bam::Format, reference: Option<&str>, args: &Cli) -> Result<Self, String> {
+        SamWriter::open(path, header, format, reference, args.sort_output, args)
     }
-}
 
-pub fn process_sam(args: &Cli) -> Result<(), Box<dyn std::error::Error>> {
+    //create an unaligned BAM, which is never sorted or indexed
+    pub(crate) fn create_unaligned(path: &str, header: &bam::Header, args: &Cli) -> Result<Self, String> {
+        SamWriter::open(path, header, bam::Format::Bam, None, false, args)
+    }
+
+    fn open(path: &str, header: &bam::Header, format: bam::Format, reference: Option<&str>, sort: bool, args: &Cli) -> Result<Self, String> {
+        let mode: &[u8] = match format {
+            bam::Format::Sam => b"w\0",
+            bam::Format::Bam => b"wb\0",
+            bam::Format::Cram => b"wc\0",
+        };
+        let c_path = CString::new(path).map_err(|_| "path contains a NUL byte".to_string())?;
+        let f = unsafe { htslib::hts_open(c_path.as_ptr(), mode.as_ptr() as *const c_char) };
+        if f.is_null() {
+            return Err(std::io::Error::last_os_error().to_string());
+        }
+        //the file is closed when the writer is dropped, also if setting it up fails below
+        //the HapQ model the hq tags were computed with
+        let mut header = header.clone();
+        if !args.no_hapq {
+            header.push_comment(args.model.header_comment().as_bytes());
+        }
+        let header = if sort { coordinate_sorted(&header) } else { bam::HeaderView::from_header(&header) };
+        let mut writer = SamWriter { f, header, path: path.to_string(), format, sorter: None, index: None, n_threads: 1 };
+        if sort {
+            writer.sorter = Some(Box::new(RecordSorter::new(&writer.header, coordinate_cmp, args.sort_mem, &args.tmp_dir())));
+            writer.index = index_type(&writer.header, format, path, args.csi);
+        }
+
+        if let (Some(level), bam::Format::Bam | bam::Format::Cram) = (args.level, format) {
+            if unsafe { htslib::hts_set_opt(f, htslib::hts_fmt_option_HTS_OPT_COMPRESSION_LEVEL, level as c_int) } != 0 {
+                return Err(format!("invalid compression level {}", level));
+            }
+        }
+        if let bam::Format::Cram = format {
+            if let Some(reference) = reference {
+                let c_ref = CString::new(reference).map_err(|_| "reference path contains a NUL byte".to_string())?;
+                if unsafe { htslib::hts_set_fai_filename(f, c_ref.as_ptr()) } != 0 {
+                    return Err(format!("failed to load reference '{}'", reference));
+                }
+            }
+            if let Some(version) = &args.cram_version {
+                let c_version = CString::new(version.as_str()).map_err(|_| format!("invalid CRAM version '{}'", version))?;
+                if unsafe { htslib::hts_set_opt(f, htslib::hts_fmt_option_CRAM_OPT_VERSION, c_version.as_ptr()) } != 0 {
+                    return Err(format!("invalid CRAM version '{}' (use 2.1, 3.0 or 3.1)", version));
+                }
+            }
+            if args.embed_ref && unsafe { htslib::hts_set_opt(f, htslib::hts_fmt_option_CRAM_OPT_EMBED_REF, 1 as c_int) } != 0 {
+                return Err("failed to enable reference embedding".into());
+            }
+        }
 
-    //detect format of both input files (i.e sam/cram/bam)
-    let asm1_format = get_format_from_path(&args.asm1)
-        .map_err(|e| format!("Failed to identify asm1 file format: {}", e))?;
-    let asm2_format = get_format_from_path(&args.asm2)
-        .map_err(|e| format!("Failed to identify asm2 file format: {}", e))?;
+        if unsafe { htslib::sam_hdr_write(f, writer.header.inner_ptr()) } != 0 {
+            return Err("failed to write header".into());
+        }
+        Ok(writer)
+    }
 
-    //ensure both input files are of the same format
-    if !formats_equal(&asm1_format, &asm2_format) {
-        return Err(format!("Input files must have the same format (found {:?} and {:?})", asm1_format, asm2_format).into());
+    //use n_threads extra threads for compression (and for building the index)
+    pub(crate) fn set_threads(&mut self, n_threads: usize) -> Result<(), BamError> {
+        self.n_threads = n_threads;
+        match unsafe { htslib::hts_set_threads(self.f, n_threads as c_int) } {
+            0 => Ok(()),
+            _ => Err(BamError::SetThreads),
+        }
     }
 
-    // read in both files
-    let mut asm1_reader = bam::Reader::from_path(&args.asm1)
-        .map_err(|e| format!("Failed to open asm1 file '{}': {}", args.asm1, e))?;
-    let mut asm2_reader = bam::Reader::from_path(&args.asm2)
-        .map_err(|e| format!("Failed to open asm2 file '{}': {}", args.asm2, e))?;
+    pub(crate) fn write(&mut self, rec: &Record) -> Result<(), Box<dyn std::error::Error>> {
+        match &mut self.sorter {
+            Some(sorter) => sorter.push(rec.clone()),
+            None => Ok(self.write_now(rec)?),
+        }
+    }
 
-    //Store headers from both input files, as these headers are the same as will be needed in output files
-    let header_asm1 = bam::Header::from_template(asm1_reader.header());
-    let header_asm2 = bam::Header::from_template(asm2_reader.header());
+    fn write_now(&mut self, rec: &Record) -> Result<(), BamError> {
+        match unsafe { htslib::sam_write1(self.f, self.header.inner_ptr(), rec.inner()) } {
+            n if n < 0 => Err(BamError::WriteRecord),
+            _ => Ok(()),
+        }
+    }
 
-    //get proper file extension for output based on input format
-    let extension = match asm1_format {
-        bam::Format::Bam => ".bam",
-        bam::Format::Sam => ".sam",
-        bam::Format::Cram => ".cram",
-    };
+    //write out sorted records, close the file and index it
+    //closing is checked here, unlike on drop, so errors at the end of the file are reported
+    pub(crate) fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(sorter) = self.sorter.take() {
+            eprintln!("Writing sorted output '{}'", self.path);
+            for rec in sorter.finish()? {
+                self.write_now(&rec?)?;
+            }
+        }
+        let ret = unsafe { htslib::hts_close(self.f) };
+        self.f = std::ptr::null_mut();
+        if ret != 0 {
+            return Err(format!("Failed to close output file '{}'", self.path).into());
+        }
+        if let Some(index) = self.index.take() {
+            let ext = match (self.format, &index) {
+                (bam::Format::Cram, _) => "crai",
+                (_, IndexType::Bai) => "bai",
+                (_, IndexType::Csi(_)) => "csi",
+            };
+            bam::index::build(&self.path, None, index, self.n_threads as u32)
+                .map_err(|e| format!("Failed to build .{} index of '{}': {}", ext, self.path, e))?;
+        }
+        Ok(())
+    }
+}
 
+impl Drop for SamWriter {
+    fn drop(&mut self) {
+        if !self.f.is_null() {
+            unsafe { htslib::hts_close(self.f); }
+        }
+    }
+}
 
-    //create writers for both outputs that share user specified prefix
-    let asm1_out_path = format!("diplinator_{}{}", args.s1, extension);
-    let asm2_out_path = format!("diplinator_{}{}", args.s2, extension);
-    //headers are same as in original files, so copy them into output
-    let mut out_asm1 = Writer::from_path(&asm1_out_path, &header_asm1, asm1_format)
-        .map_err(|e| format!("Failed to create output file '{}': {}", asm1_out_path, e))?;
-    let mut out_asm2 = Writer::from_path(&asm2_out_path, &header_asm2, asm2_format)
-        .map_err(|e| format!("Failed to create output file '{}': {}", asm2_out_path, e))?;
-
-    //if dealing with a cram file, must set reference fastas and ensure the user provided those
-    if let bam::Format::Cram = asm1_format {
-        //set fasta reference for both asm1 reader and writer
-        if let Some(reference) = &args.ref1 {
-            asm1_reader.set_reference(reference)
-                .map_err(|e| format!("Failed to set reference for asm1 Reader: {}", e))?;
-            out_asm1.set_reference(reference)
-                .map_err(|e| format!("Failed to set reference for asm1 Writer: {}", e))?;
+//header of coordinate-sorted output: @HD gets SO:coordinate, and name-sort subsort/grouping tags are dropped
+fn coordinate_sorted(header: &bam::Header) -> bam::HeaderView {
+    let text = String::from_utf8_lossy(&header.to_bytes()).into_owned();
+    let mut hd = String::from("@HD\tVN:1.6");
+    let mut lines = Vec::new();
+    for line in text.lines().filter(|l| !l.is_empty()) {
+        if line.starts_with("@HD") {
+            hd = line.split('\t').filter(|f| !["SO:", "SS:", "GO:"].iter().any(|t| f.starts_with(t))).collect::<Vec<&str>>().join("\t");
         } else {
-            //throw error reference fasta was not provided on a cram input
-            return Err("Input format is CRAM, but no reference FASTA for asm1 provided. Use --ref1 <FILE>".into());
+            lines.push(line);
         }
-    } else if args.ref1.is_some() {
-        //warn user that asm1 reference will be ignored since the input isn't cram
-        eprintln!("Warning: --ref1 is ignored for non-CRAM input");
     }
+    let mut sorted = format!("{}\tSO:coordinate\n", hd);
+    for line in lines {
+        sorted.push_str(line);
+        sorted.push('\n');
+    }
+    bam::HeaderView::from_bytes(sorted.as_bytes())
+}
 
-    //repeat above for the asm2 cram file
-    if let bam::Format::Cram = asm2_format {
-        if let Some(reference) = &args.ref2 {
-            asm2_reader.set_reference(reference)
-                .map_err(|e| format!("Failed to set reference for asm2 Reader: {}", e))?;
-            out_asm2.set_reference(reference)
-                .map_err(|e| format!("Failed to set reference for asm2 Writer: {}", e))?;
-        } else {
-            return Err("Input format is CRAM, but no reference FASTA for asm2 provided. Use --ref2 <FILE>".into());
-        }
-    } else if args.ref2.is_some() {
-        eprintln!("Warning: --ref2 is ignored for non-CRAM input");
-    }
-
-    //set threads
-    //if user specifies less than 4, set to 4 (1 thread for each reader and each writer is needed)
-    let avail_threads = max(4, args.threads);
-    //assign write:reader threads (ideally) 3:1
-    let r = max(1, avail_threads / 8);
-    //if any additional threads available, assign to writer
-    //if num threads is odd, leave one idle
-    let w = (avail_threads - (2 * r)) / 2;
-
-    //assign threads to each reader/writer pair
-    asm1_reader.set_threads(r)?;
-    asm2_reader.set_threads(r)?;
-    out_asm1.set_threads(w)?;
-    out_asm2.set_threads(w)?;
-
-    //create peakable iterators of each file
-    let mut asm1_iter = asm1_reader.records().peekable();
-    let mut asm2_iter = asm2_reader.records().peekable();
-
-    //vectors that store all alignments of one read (cluster of alignments)
-    //initiallize capacity to 10 to account for supplemental and secondary alignments
-    let mut cluster_asm1: Vec<Record> = Vec::with_capacity(10);
-    let mut cluster_asm2: Vec<Record> = Vec::with_capacity(10);
-
-    //initialize counts for summary statistics printed to terminal
-    let mut count_asm1: u64 = 0;
-    let mut count_asm2: u64 = 0;
-    let mut count_equal: u64 = 0;
-    let mut count_unmapped: u64 = 0;
-
-    //iterate thorugh both files until they are both exhaused
-    while asm1_iter.peek().is_some() || asm2_iter.peek().is_some() {
-
-        //move forward by one read for both files
-        get_clusters(&mut asm1_iter, &mut cluster_asm1)?;
-        get_clusters(&mut asm2_iter, &mut cluster_asm2)?;
-
-        // check for possible errors such as:
-        //end of file / empty cluster / clusters don't represent same read in both files
-        match (cluster_asm1.first(), cluster_asm2.first()) {
-
-            (None, None) => break,           // end of file reached for both, should occur at same iteration
-            (Some(_), None) | (None, Some(_)) => {
-                //one file has ended earlier than the other- throw error
-                return Err("alignment streams out of sync: one file ended earlier".into());
-            }
-            (Some(m), Some(p)) => {
-                //read ID is not the same in both clusters- throw error
-                if m.qname() != p.qname() {
-                    return Err(format!(
-                        "alignment streams out of sync: asm1={} asm2={}",
-                        String::from_utf8_lossy(m.qname()),
-                        String::from_utf8_lossy(p.qname()),
-                    ).into());
-                }
-            }
+//index of sorted output: .crai for CRAM, .bai for BAM unless --csi or a contig is too long for it
+//SAM and stdout cannot be indexed
+fn index_type(header: &bam::HeaderView, format: bam::Format, path: &str, csi: bool) -> Option<IndexType> {
+    if path == "-" || matches!(format, bam::Format::Sam) {
+        eprintln!("Warning: sorted output '{}' is not indexed, only BAM/CRAM files (not SAM or stdout) can be", path);
+        return None;
+    }
+    //.bai addresses positions up to 2^29
+    let too_long = (0..header.target_count()).any(|tid| header.target_len(tid).unwrap_or(0) >= 1 << 29);
+    if csi || too_long { Some(IndexType::Csi(14)) } else { Some(IndexType::Bai) }
+}
+
+//read name collation of a name-sorted input from its @HD line, None if it is not name-sorted
+//samtools marks lexicographic order with SS:queryname:lexicographical, plain SO:queryname is natural order
+pub(crate) fn header_name_order(view: &bam::HeaderView) -> Option<NameOrder> {
+    if hd_tag(view, "SO").as_deref() != Some("queryname") {
+        return None;
+    }
+    match hd_tag(view, "SS") {
+        Some(ss) if ss.contains("lexicographic") => Some(NameOrder::Lexicographic),
+        _ => Some(NameOrder::Natural),
+    }
+}
+
+//records of an input read through a reader the stream owns, so it does not borrow the reader
+pub(crate) struct OwnedRecords(pub bam::Reader);
+
+impl Iterator for OwnedRecords {
+    type Item = Result<Record, BamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut rec = Record::new();
+        match self.0.read(&mut rec)? {
+            Ok(()) => Some(Ok(rec)),
+            Err(e) => Some(Err(e)),
         }
+    }
+}
 
-        //get cluster with the higher alignment score, returns the Winner enum and HAPQ
-        let (winner, hapq) = compare_clusters(&mut cluster_asm1, &mut cluster_asm2, &args)?;
+//value of a tag on the @HD header line, e.g. SO for the sort order
+pub(crate) fn hd_tag(view: &bam::HeaderView, tag: &str) -> Option<String> {
+    let text = String::from_utf8_lossy(view.as_bytes()).into_owned();
+    let hd = text.lines().find(|l| l.starts_with("@HD"))?;
+    let prefix = format!("{}:", tag);
+    hd.split('\t').find_map(|f| f.strip_prefix(prefix.as_str())).map(|v| v.to_string())
+}
 
-        //logic for which file to write read to given score comparison output
-        match winner {
-            //asm1 clear winner, write to out_asm1
-            crate::Winner::Asm1 => {
-                count_asm1 += 1; // increment read counter
-                for rec in cluster_asm1.iter_mut() {
-                    if let Some(hq) = hapq { rec.push_aux(b"hq", Aux::U8(hq))?; }
-                    out_asm1.write(rec)?;
-                }
-            }
-            //asm2 clear winner, write to out_asm2
-            crate::Winner::Asm2 => {
-                count_asm2 += 1; // increment read counter
-                for rec in cluster_asm2.iter_mut() {
-                    if let Some(hq) = hapq { rec.push_aux(b"hq", Aux::U8(hq))?; }
-                    out_asm2.write(rec)?;
-                }
-            }
-            crate::Winner::Both => {
-                count_equal += 1; // increment read counter
-                //if user specifices --both, write equal scoring reads to output files
-                if args.both {
-                    for rec in cluster_asm1.iter_mut() {
-                        if let Some(hq) = hapq { rec.push_aux(b"hq", Aux::U8(hq))?; }
-                        out_asm1.write(rec)?;
-                    }
-                    for rec in cluster_asm2.iter_mut() {
-                        if let Some(hq) = hapq { rec.push_aux(b"hq", Aux::U8(hq))?; }
-                        out_asm2.write(rec)?;
+//ID and sample (SM, '*' if missing) of every @RG line of a header
+pub(crate) fn read_group_samples(view: &bam::HeaderView) -> Vec<(String, String)> {
+    let text = String::from_utf8_lossy(view.as_bytes()).into_owned();
+    text.lines().filter(|l| l.starts_with("@RG\t")).filter_map(|line| {
+        let field = |tag: &str| line.split('\t').find_map(|f| f.strip_prefix(tag)).map(|v| v.to_string());
+        Some((field("ID:")?, field("SM:").unwrap_or_else(|| "*".to_string())))
+    }).collect()
+}
+
+//read group (RG tag) of a read, from the first of its records that has one
+pub(crate) fn read_group(cluster: &[Record]) -> Option<String> {
+    cluster.iter().find_map(|rec| match rec.aux(b"RG") {
+        Ok(Aux::String(rg)) => Some(rg.to_string()),
+        _ => None,
+    })
+}
+
+//build one header holding the @SQ lines of every input, so one stream can hold records of all inputs
+//contig names found in several inputs get that input's prefix, so reads keep pointing at their own haplotype
+//@HD is taken from the first input, @RG/@PG lines are kept once per ID and @CO lines once per text
+//returns the header and how records of every input are moved into it
+pub(crate) fn merged_header(views: &[&bam::HeaderView], labels: &[String], prefixes: &[String]) -> Result<(bam::Header, MergedTargets), Box<dyn std::error::Error>> {
+    let texts: Vec<String> = views.iter().map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned()).collect();
+    let sq_name = |line: &str| line.split('\t').find_map(|f| f.strip_prefix("SN:")).unwrap_or("").to_string();
+
+    //number of inputs holding every contig name, to find the ones that collide
+    let mut n_inputs: HashMap<String, usize> = HashMap::new();
+    for text in texts.iter() {
+        let names: HashSet<String> = text.lines().filter(|l| l.starts_with("@SQ")).map(sq_name).collect();
+        for name in names {
+            *n_inputs.entry(name).or_insert(0) += 1;
+        }
+    }
+
+    let mut hd: Option<String> = None;
+    let mut sq: Vec<String> = Vec::new();
+    let mut other: Vec<String> = Vec::new();
+    //contig name in the merged header -> label of the input it came from, to catch collisions left after prefixing
+    let mut contigs: HashMap<String, &String> = HashMap::new();
+    let mut seen_ids: HashSet<String> = HashSet::new();
+    let mut offsets = Vec::with_capacity(views.len());
+    let mut renamed = 0;
+
+    for ((text, view), (label, prefix)) in texts.iter().zip(views.iter()).zip(labels.iter().zip(prefixes.iter())) {
+        offsets.push(sq.len() as i32);
+        for line in text.lines().filter(|l| !l.is_empty()) {
+            let rec_type = &line[..line.len().min(3)];
+            match rec_type {
+                "@HD" => { if hd.is_none() { hd = Some(line.to_string()); } }
+                "@SQ" => {
+                    let mut name = sq_name(line);
+                    let mut line = line.to_string();
+                    if n_inputs[&name] > 1 {
+                        name = format!("{}{}", prefix, name);
+                        line = line.split('\t')
+                            .map(|f| if f.starts_with("SN:") { format!("SN:{}", name) } else { f.to_string() })
+                            .collect::<Vec<String>>().join("\t");
+                        renamed += 1;
                     }
-                //default behavior is randomly assign equal scoring read to one file
-                } else {
-                    //hash read name and use last bit value to assign to asm1 or asm2
-                    //ensures that assignments will be reproducible
-                    match crate::choose_random(cluster_asm1[0].qname()) {
-                        crate::Winner::Asm1 => {
-                            for rec in cluster_asm1.iter_mut() {
-                                if let Some(hq) = hapq { rec.push_aux(b"hq", Aux::U8(hq))?; }
-                                out_asm1.write(rec)?;
-                            }
-                        }
-                        _ => {
-                            for rec in cluster_asm2.iter_mut() {
-                                if let Some(hq) = hapq { rec.push_aux(b"hq", Aux::U8(hq))?; }
-                                out_asm2.write(rec)?;
-                            }
-                        }
+                    if let Some(first) = contigs.insert(name.clone(), label) {
+                        return Err(format!("Contig '{}' is present in both {} and {}; merged output needs unique contig names (see --contig-prefixes)",
+                            name, first, label).into());
                     }
+                    sq.push(line);
                 }
-            }
-            crate::Winner::Unmapped => {
-                count_unmapped += 1;
-                match args.unmapped {
-                    crate::cli::UnmappedDest::Asm1 => {
-                        for rec in cluster_asm1.iter_mut() {
-                            if let Some(hq) = hapq { rec.push_aux(b"hq", Aux::U8(hq))?; }
-                            out_asm1.write(rec)?;
-                        }
-                    }
-                    crate::cli::UnmappedDest::Asm2 => {
-                        for rec in cluster_asm2.iter_mut() {
-                            if let Some(hq) = hapq { rec.push_aux(b"hq", Aux::U8(hq))?; }
-                            out_asm2.write(rec)?;
-                        }
-                    }
-                    crate::cli::UnmappedDest::Discard => {}
+                "@RG" | "@PG" => {
+                    let id = line.split('\t').find_map(|f| f.strip_prefix("ID:")).unwrap_or("");
+                    if seen_ids.insert(format!("{}{}", rec_type, id)) { other.push(line.to_string()); }
                 }
+                _ => { if seen_ids.insert(line.to_string()) { other.push(line.to_string()); } }
             }
         }
+        //make sure @SQ lines and the binary target list agree, otherwise offsets would be wrong
+        if sq.len() as i32 - offsets[offsets.len() - 1] != view.target_count() as i32 {
+            return Err(format!("Header of {} has {} @SQ lines but {} targets", label,
+                sq.len() as i32 - offsets[offsets.len() - 1], view.target_count()).into());
+        }
+    }
+    if renamed > 0 {
+        eprintln!("{} contigs occur in several inputs and were prefixed in the merged header", renamed);
+    }
+
+    let mut text = String::new();
+    for line in hd.iter().chain(sq.iter()).chain(other.iter()) {
+        text.push_str(line);
+        text.push('\n');
+    }
+    let view = bam::HeaderView::from_bytes(text.as_bytes());
+    let collided = n_inputs.into_iter().filter(|(_, n)| *n > 1).map(|(name, _)| name).collect();
+    Ok((bam::Header::from_template(&view), MergedTargets { offsets, collided, prefixes: prefixes.to_vec() }))
+}
+
+//how the records of every input are moved into a merged header
+pub(crate) struct MergedTargets {
+    //offset added to the target ids of every input
+    offsets: Vec<i32>,
+    //contig names found in several inputs, which got the input's prefix
+    collided: HashSet<String>,
+    prefixes: Vec<String>,
+}
 
+impl MergedTargets {
+    //move a record of input src into the merged header, including the contig names in its SA tag
+    pub(crate) fn apply(&self, rec: &mut Record, src: usize) -> Result<(), Box<dyn std::error::Error>> {
+        shift_tids(rec, self.offsets[src]);
+        if self.collided.is_empty() {
+            return Ok(());
+        }
+        let sa = match rec.aux(b"SA") {
+            Ok(Aux::String(s)) => s.to_string(),
+            _ => return Ok(()),
+        };
+        let renamed: String = sa.split_terminator(';')
+            .map(|e| match e.split_once(',') {
+                Some((name, rest)) if self.collided.contains(name) => format!("{}{},{};", self.prefixes[src], name, rest),
+                _ => format!("{};", e),
+            })
+            .collect();
+        if renamed != sa {
+            rec.remove_aux(b"SA")?;
+            rec.push_aux(b"SA", Aux::String(&renamed))?;
+        }
+        Ok(())
     }
-    //print summarry statistics to terminal
-    let total = count_asm1 + count_asm2 + count_equal + count_unmapped;
-    eprintln!("Reads aligned better to {}: {} ({:.1}%)", args.s1, count_asm1, count_asm1 as f64 / total as f64 * 100.0);
-    eprintln!("Reads aligned better to {}: {} ({:.1}%)", args.s2, count_asm2, count_asm2 as f64 / total as f64 * 100.0);
-    eprintln!("Reads with equal scores:     {} ({:.1}%)", count_equal, count_equal as f64 / total as f64 * 100.0);
-    eprintln!("Reads unmapped to both:      {} ({:.1}%)", count_unmapped, count_unmapped as f64 / total as f64 * 100.0);
-    eprintln!("Total reads parsed:                 {}", total);
-Ok(())
+}
+
+//move a record's target ids into the merged header, unmapped ids (-1) stay as they are
+fn shift_tids(rec: &mut Record, offset: i32) {
+    if rec.tid() >= 0 { rec.set_tid(rec.tid() + offset); }
+    if rec.mtid() >= 0 { rec.set_mtid(rec.mtid() + offset); }
 }
 
 //function to move ahead one read group at a time for SAM/BAM/CRAM
-fn get_clusters<I>(records: &mut Peekable<I>, cluster: &mut Vec<Record>)-> Result<(), Box<dyn std::error::Error>>
+pub(crate) fn get_clusters<I>(records: &mut Peekable<I>, cluster: &mut Vec<Record>)-> Result<(), Box<dyn std::error::Error>>
 where
     I: Iterator<Item= Result<Record,BamError>>,
 {
@@ -313,29 +434,31 @@ where
     Ok(())
 }
 
-//helper function to get weighted score of reads using a specified tag (AS or ms)
+//helper function to get weighted score of reads using the --score source (AS tag by default)
 //for supplental alignments read segments may have overlapping alignments in read coords
 //want to take average alignment score for every base in the read to determine total score
-fn get_weighted_score(cur_clust : &mut Vec<Record>, tag: &[u8]) -> Result<(f32, u32), Box<dyn std::error::Error>> {
+pub(crate) fn get_weighted_score<R: Borrow<Record>>(cur_clust : &[R], scorer: &Scorer) -> Result<(f32, u32), Box<dyn std::error::Error>> {
     //get read name
-    let qname = String::from_utf8_lossy(cur_clust[0].qname()).into_owned();
+    let qname = String::from_utf8_lossy(cur_clust[0].borrow().qname()).into_owned();
     let mut sum_alignment_lens = 0;
-    let mut sum_alignment_scores = 0;
+    let mut sum_alignment_scores = 0.0;
     let mut n_splits: u32 = 0;
     //store all read intervals mapping anywhere to take union of later (filter out overlapping segments)
     let mut read_intervals: Vec<(u32, u32)> = Vec::with_capacity(cur_clust.len());
+    //placement of every segment on the reference, only needed for the --chain penalty
+    let mut segments = Vec::new();
 
     //get full read length from the first non-secondary record's CIGAR
     //sum of all query-consuming ops (M/I/=/X/S/H) gives original read length even for supplementaries
     let mut read_len: u32 = 0;
-    for rec in cur_clust.iter() {
+    for rec in cur_clust.iter().map(|r| r.borrow()) {
         if !rec.is_secondary() {
             read_len = get_read_len(rec);
             break;
         }
     }
 
-    for rec in cur_clust {
+    for rec in cur_clust.iter().map(|r| r.borrow()) {
         //do not factor secondary alignments into choosing best alignment,
         // but still output them with the cluster, we don't want to lose them
         if rec.is_secondary() {continue};
@@ -347,25 +470,17 @@ fn get_weighted_score(cur_clust : &mut Vec<Record>, tag: &[u8]) -> Result<(f32,
 
         sum_alignment_lens += alen;
 
-        //extract alignment score as i32, throw error if tag missing
-        //is not the same integer type in every sam file so check every possile type to be robust
-        let alignment_score: i32 = match rec.aux(tag) {
-            Ok(Aux::I8(v))  => v as i32,
-            Ok(Aux::I16(v)) => v as i32,
-            Ok(Aux::I32(v)) => v,
-            Ok(Aux::U8(v))  => v as i32,
-            Ok(Aux::U16(v)) => v as i32,
-            Ok(Aux::U32(v)) => v as i32,
-            _ => return Err(format!("Read '{}' is missing the '{}' tag",
-            String::from_utf8_lossy(rec.qname()),
-            String::from_utf8_lossy(tag)).into()),
-        };
+        //throw error if the score (or what it is derived from) is missing
+        let alignment_score = scorer.record(rec)?;
 
         sum_alignment_scores += alignment_score;
 
         //get the read (query) coordinates of the start of the alignment
         let read_start = get_query_start(rec);
-        read_intervals.push((read_start, read_start + alen))
+        read_intervals.push((read_start, read_start + alen));
+        if scorer.chain() {
+            segments.push(segment(rec));
+        }
     }
     //this should not happen, but handle just in case
     if sum_alignment_lens <= 0 {
@@ -381,53 +496,151 @@ fn get_weighted_score(cur_clust : &mut Vec<Record>, tag: &[u8]) -> Result<(f32,
     //average alignment score per base across all aligning segments
     // multiplied by unique aligned bases, scaled by coverage fraction of the read
     let cov_fraction = read_bps_aligned as f32 / read_len as f32;
-    return Ok(((sum_alignment_scores as f32 / sum_alignment_lens as f32) * read_bps_aligned as f32 * cov_fraction, n_splits));
+    //minus the penalty of split alignments that are no collinear chain (--chain)
+    let chain_penalty = scorer.chain_penalty(&mut segments) as f32;
+    return Ok(((sum_alignment_scores as f32 / sum_alignment_lens as f32) * read_bps_aligned as f32 * cov_fraction - chain_penalty, n_splits));
 
 }
 
-//choose which alignment block to keep
-fn compare_clusters<'a>(clust1:&'a mut Vec<Record>, clust2:&'a mut Vec<Record>, args:&Cli) ->  Result<(crate::Winner, Option<u8>), Box<dyn std::error::Error>> {
-
-    //if either cluster is empty there is a file sync issue as every cluster should have at least one record
-    if clust1.is_empty() || clust2.is_empty() {
-        return Err("Fatal Error: Attempted to compare empty read clusters. This usually indicates a file sync issue.".into());
+//placement of an aligned record on the read and the reference
+fn segment(rec: &Record) -> Segment<i32> {
+    let qstart = get_query_start(rec);
+    Segment {
+        qstart,
+        qend: qstart + get_alignment_len(rec),
+        target: rec.tid(),
+        reverse: rec.is_reverse(),
+        tstart: rec.pos(),
+        tend: rec.cigar().end_pos(),
     }
+}
 
-    //check if read is unmapped in either or both files
-    let unmappeds = (clust1[0].is_unmapped(), clust2[0].is_unmapped());
-
-    //handle unmapped read cases
+//chain of the non-secondary alignments of every mate of a read (0 for single-end reads, 1 and 2 for mates) as hc tag value:
+//one letter per junction in read order, '.' collinear, 'C' to another contig, 'S' to the other strand,
+//'B' backwards and 'O' overlapping on the contig; None for mates aligned in one piece or not at all
+pub(crate) fn chains(cluster: &[Record]) -> [Option<String>; 3] {
+    [0, 1, 2].map(|m| {
+        let mut segments: Vec<Segment<i32>> = cluster.iter()
+            .filter(|r| mate(r) == m && !r.is_secondary() && !r.is_unmapped())
+            .map(segment)
+            .collect();
+        if segments.len() < 2 { return None; }
+        Some(crate::chain_junctions(&mut segments).iter().map(|j| j.code()).collect())
+    })
+}
 
-    match unmappeds {
-        (true, true) => { return Ok((crate::Winner::Unmapped, None)); }, //unmapped in both
-        //if read only maps to one hap then that hap is the winner
-        (true, false) => return Ok((crate::Winner::Asm2, if args.no_hapq { None } else { Some(60u8) })), //  mapped in asm2
-        (false, true) => return Ok((crate::Winner::Asm1, if args.no_hapq { None } else { Some(60u8) })), //  mapped in asm1
-        _ => {} //mapped in both continue to check below
+//HapQ cap from how uniquely a read is placed within one input (--hapq-multimap), None without any signal:
+//the lowest of the MAPQ of its non-secondary records (255 is unavailable), the gap between the s1 and s2 chaining scores
+//(in bases), between AS and XS, and between each primary's score and its best secondary's, as HapQ of the two
+pub(crate) fn placement_cap(cluster: &[Record], scorer: &Scorer, args: &Cli) -> Option<u8> {
+    let (signals, match_sc, model) = (&args.hapq_multimap, args.match_sc, &args.model);
+    let mut cap: Option<u8> = None;
+    let mut lower = |q: u8| cap = Some(cap.map_or(q, |c| c.min(q)));
+    for rec in cluster.iter().filter(|r| !r.is_secondary() && !r.is_unmapped()) {
+        if signals.contains(&MultimapSignal::Mapq) && rec.mapq() != 255 {
+            lower(rec.mapq().min(60));
+        }
+        if signals.contains(&MultimapSignal::S2) {
+            if let (Some(s1), Some(s2)) = (int_tag(rec, b"s1"), int_tag(rec, b"s2")) {
+                lower(crate::compute_hapq(s1 as f32, s2 as f32, 1, 1.0, model));
+            }
+        }
+        if signals.contains(&MultimapSignal::Xs) {
+            if let (Some(a), Some(xs)) = (int_tag(rec, b"AS"), int_tag(rec, b"XS")) {
+                lower(crate::compute_hapq(a as f32, xs as f32, 1, match_sc, model));
+            }
+        }
+        if signals.contains(&MultimapSignal::Secondary) && !rec.is_supplementary() {
+            let best_secondary = cluster.iter()
+                .filter(|r| r.is_secondary() && mate(r) == mate(rec))
+                .filter_map(|r| scorer.record(r).ok())
+                .fold(None, |best: Option<f64>, s| Some(best.map_or(s, |b| b.max(s))));
+            if let (Ok(primary), Some(secondary)) = (scorer.record(rec), best_secondary) {
+                lower(crate::compute_hapq(primary as f32, secondary as f32, 1, match_sc, model));
+            }
+        }
     }
+    cap
+}
 
-    //determine what field we are using to compare alignment score
-    //default is using alignment score (AS:i:) but using ms:i: can be set by user wiht --ms
-    let tag: &[u8] = if args.ms { b"ms" } else { b"AS" };
-
-    //get score and number of non-secondary alignment segments for each cluster
-    let (score1, n_splits1) = get_weighted_score(clust1, tag)?;
-    let (score2, n_splits2) = get_weighted_score(clust2, tag)?;
+//reverse complement of a base sequence as returned by Seq::as_bytes
+pub(crate) fn revcomp(seq: &[u8]) -> Vec<u8> {
+    seq.iter().rev().map(|&b| match b {
+        b'A' => b'T', b'C' => b'G', b'G' => b'C', b'T' => b'A',
+        b'M' => b'K', b'K' => b'M', b'R' => b'Y', b'Y' => b'R',
+        b'B' => b'V', b'V' => b'B', b'D' => b'H', b'H' => b'D',
+        other => other,
+    }).collect()
+}
 
-    //return respective winner depending on which AS is higher,
-    //both is a special case that can be determined by user input
-    if score1 > score2 {
-        let hapq = if args.no_hapq { None } else { Some(crate::compute_hapq(score1, score2, n_splits1, args.match_sc)) };
-        Ok((crate::Winner::Asm1, hapq))
-    } else if score1 < score2 {
-        let hapq = if args.no_hapq { None } else { Some(crate::compute_hapq(score2, score1, n_splits2, args.match_sc)) };
-        Ok((crate::Winner::Asm2, hapq))
+//mate of a record within its fragment: 1 for READ1, 2 for READ2, 0 for single-end reads
+pub(crate) fn mate(rec: &Record) -> u8 {
+    if !rec.is_paired() {
+        0
+    } else if rec.is_first_in_template() {
+        1
+    } else if rec.is_last_in_template() {
+        2
     } else {
-        let hapq = if args.no_hapq { None } else { Some(0u8) };
-        Ok((crate::Winner::Both, hapq))
+        0
+    }
+}
+
+//helper function to get the weighted score of a paired-end fragment, whose mates share one cluster
+//every mate is scored on its own alignments like a single read and the mate scores are summed,
+//a fragment whose primary alignments form a proper pair on one contig gets pair_bonus on top
+//splits are counted per mate (the mate with the most), so the other mate is not taken for a split
+//returns None if no mate is mapped
+pub(crate) fn get_fragment_score(cur_clust: &[Record], scorer: &Scorer, pair_bonus: f32) -> Result<Option<(f32, u32)>, Box<dyn std::error::Error>> {
+    let mut score = 0.0;
+    let mut n_splits: u32 = 0;
+    let mut mapped = false;
+    let mut primaries: [Option<&Record>; 3] = [None; 3];
+    for m in 0..=2 {
+        let recs: Vec<&Record> = cur_clust.iter().filter(|r| mate(r) == m && !r.is_unmapped()).collect();
+        //a mate without a non-secondary alignment is unmapped (in this haplotype)
+        if recs.iter().all(|r| r.is_secondary()) { continue; }
+        let (mate_score, mate_splits) = get_weighted_score(&recs, scorer)?;
+        score += mate_score;
+        n_splits = n_splits.max(mate_splits);
+        mapped = true;
+        primaries[m as usize] = recs.iter().copied().find(|r| !r.is_secondary() && !r.is_supplementary());
+    }
+    if !mapped {
+        return Ok(None);
+    }
+    //the mate fields must point at the other primary, which is not the case for a promoted secondary
+    if let (Some(r1), Some(r2)) = (primaries[1], primaries[2]) {
+        if r1.is_proper_pair() && r2.is_proper_pair() && r1.tid() == r2.tid()
+            && r1.mtid() == r2.tid() && r1.mpos() == r2.pos() && r2.mpos() == r1.pos() {
+            score += pair_bonus;
+        }
     }
+    Ok(Some((score, n_splits)))
+}
+
+//length of a read for HapQ calibration: the longest of its non-secondary mapped records (of either mate)
+pub(crate) fn read_length(cluster: &[Record]) -> u32 {
+    cluster.iter().filter(|r| !r.is_secondary() && !r.is_unmapped()).map(get_read_len).max().unwrap_or(0)
 }
 
+//stats of the non-secondary records of a read that break ties in score (--tie-break)
+pub(crate) fn tie_stats(cluster: &[Record], splits: u32, header: &bam::HeaderView) -> TieStats {
+    let aligned: Vec<&Record> = cluster.iter().filter(|r| !r.is_secondary() && !r.is_unmapped()).collect();
+    let mapq = aligned.iter().filter(|r| !r.is_supplementary()).map(|r| r.mapq()).filter(|&q| q != 255).min();
+    let nm = aligned.iter().map(|r| match r.aux(b"NM") {
+        Ok(Aux::U8(v)) => Some(v as i64),
+        Ok(Aux::U16(v)) => Some(v as i64),
+        Ok(Aux::U32(v)) => Some(v as i64),
+        Ok(Aux::I8(v)) => Some(v as i64),
+        Ok(Aux::I16(v)) => Some(v as i64),
+        Ok(Aux::I32(v)) => Some(v as i64),
+        _ => None,
+    }).sum();
+    let span = aligned.iter().map(|r| get_alignment_len(r) as u64).sum();
+    let locus = aligned.iter().find(|r| !r.is_supplementary()).map(|r| (header.tid2name(r.tid() as u32).to_vec(), r.pos()));
+    TieStats { splits, mapq, nm, span, locus }
+}
 
 //function to get full original read length from CIGAR string
 //sums all query-consuming operations: M/I/=/X/S/H
@@ -490,3 +703,64 @@ fn get_query_start(rec: &Record) -> u32 {
 
 }
 
+//integer value of an aux tag, None if it is missing or not an integer
+pub(crate) fn int_tag(rec: &Record, tag: &[u8]) -> Option<i64> {
+    match rec.aux(tag) {
+        Ok(Aux::I8(v)) => Some(v as i64),
+        Ok(Aux::I16(v)) => Some(v as i64),
+        Ok(Aux::I32(v)) => Some(v as i64),
+        Ok(Aux::U8(v)) => Some(v as i64),
+        Ok(Aux::U16(v)) => Some(v as i64),
+        Ok(Aux::U32(v)) => Some(v as i64),
+        _ => None,
+    }
+}
+
+//PAF line for a SAM record, with the columns minimap2 would write (but no cg/cs strings)
+//the header resolves the target name and length
+pub(crate) fn to_paf_line(rec: &Record, header: &bam::HeaderView) -> String {
+    let qname = String::from_utf8_lossy(rec.qname());
+    if rec.is_unmapped() || rec.tid() < 0 {
+        return format!("{}\t{}\t0\t0\t*\t*\t0\t0\t0\t0\t0\t0", qname, rec.seq_len());
+    }
+    let qstart = get_query_start(rec);
+    let qend = qstart + get_alignment_len(rec);
+    let strand = if rec.is_reverse() { '-' } else { '+' };
+    let tid = rec.tid() as u32;
+
+    //aligned columns and indel bases, NM counts mismatches plus indel bases
+    let (mut aligned, mut indels) = (0i64, 0i64);
+    for c in rec.cigar().iter() {
+        match c {
+            Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) => aligned += *l as i64,
+            Cigar::Ins(l) | Cigar::Del(l) => indels += *l as i64,
+            _ => {}
+        }
+    }
+    let nm = int_tag(rec, b"NM");
+    let matches = aligned - (nm.unwrap_or(indels) - indels).max(0);
+
+    let mut line = format!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\ttp:A:{}",
+        qname, get_read_len(rec), qstart, qend, strand,
+        String::from_utf8_lossy(header.tid2name(tid)), header.target_len(tid).unwrap_or(0),
+        rec.pos(), rec.cigar().end_pos(), matches.max(0), aligned + indels, rec.mapq(),
+        if rec.is_secondary() { 'S' } else { 'P' });
+    for tag in [b"NM", b"ms", b"AS"] {
+        if let Some(v) = int_tag(rec, tag) {
+            line.push_str(&format!("\t{}:i:{}", String::from_utf8_lossy(tag), v));
+        }
+    }
+    line
+}
+
+//unmapped record holding only a read name, for writing a read that is unmapped in PAF input as SAM/BAM/CRAM
+pub(crate) fn unmapped_record(qname: &[u8]) -> Record {
+    let mut rec = Record::new();
+    rec.set(qname, None, &[], &[]);
+    rec.set_tid(-1);
+    rec.set_pos(-1);
+    rec.set_mtid(-1);
+    rec.set_mpos(-1);
+    rec.set_flags(0x4);
+    rec
+}
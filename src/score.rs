@@ -0,0 +1,380 @@
+use std::ffi::CString;
+
+use rust_htslib::bam::{record::Aux, HeaderView, Record};
+
+use crate::cli::{ChainPenalties, Cli, ScoreScheme, ScoreSource};
+use crate::refs::Fasta;
+use crate::{Junction, Segment};
+
+//alignment score of single records or PAF/GAF lines, read from a tag or derived from the differences to the reference
+//a record or line without what its score is taken from is an error, never a score of 0
+pub(crate) struct Scorer {
+    source: ScoreSource,
+    scheme: ScoreScheme,
+    //reference of the input's records with --score ref
+    reference: Option<RefSeqs>,
+    //penalties of split alignments that are no collinear chain, with --chain
+    chain: Option<ChainPenalties>,
+}
+
+//reference bases of one input: its FASTA and the contig name of every target id of its header
+struct RefSeqs {
+    fasta: Fasta,
+    names: Vec<CString>,
+}
+
+//one alignment to score: a SAM/BAM/CRAM record, or the fields of a PAF/GAF line
+enum Aln<'a> {
+    Rec(&'a Record),
+    Line(Vec<&'a str>),
+}
+
+//differences of one alignment to the reference
+#[derive(Default)]
+struct Diffs {
+    matches: u64,
+    mismatches: u64,
+    gap_opens: u64,
+    gap_len: u64,
+}
+
+//CIGAR operations summed up: aligned bases (M/=/X), of those = and X, and insertions/deletions (N introns are not gaps)
+#[derive(Default)]
+struct CigarSums {
+    aligned: u64,
+    equal: u64,
+    diff: u64,
+    plain_m: bool,
+    gap_opens: u64,
+    gap_len: u64,
+}
+
+impl ScoreScheme {
+    fn score(&self, d: &Diffs) -> f64 {
+        self.matched * d.matches as f64 - self.mismatch * d.mismatches as f64
+            - self.gap_open * d.gap_opens as f64 - self.gap_ext * d.gap_len as f64
+    }
+}
+
+impl Scorer {
+    pub(crate) fn new(args: &Cli) -> Self {
+        Scorer {
+            source: args.score_source(),
+            scheme: args.score_scheme,
+            reference: None,
+            chain: args.chain.then_some(args.chain_penalties),
+        }
+    }
+
+    //true with --chain, whose penalties need the segments of every read
+    pub(crate) fn chain(&self) -> bool {
+        self.chain.is_some()
+    }
+
+    //penalty of a read whose segments are not one collinear chain, 0 without --chain
+    pub(crate) fn chain_penalty<T: PartialEq>(&self, segments: &mut [Segment<T>]) -> f64 {
+        let Some(p) = &self.chain else { return 0.0 };
+        crate::chain_junctions(segments).iter().map(|j| match j {
+            Junction::Collinear => 0.0,
+            Junction::Contig => p.contig,
+            Junction::Strand => p.strand,
+            Junction::Order => p.order,
+            Junction::Overlap => p.overlap,
+        }).sum()
+    }
+
+    //true with --score ref, whose scores are computed from the reference FASTA and the read sequence
+    pub(crate) fn needs_reference(&self) -> bool {
+        self.source == ScoreSource::Ref
+    }
+
+    //the same scorer reading the reference bases of records with this header from a FASTA
+    pub(crate) fn with_reference(mut self, fasta: &str, header: &HeaderView) -> Result<Self, String> {
+        let names = header.target_names().into_iter()
+            .map(|n| CString::new(n).map_err(|_| format!("Invalid contig name '{}'", String::from_utf8_lossy(n))))
+            .collect::<Result<Vec<CString>, String>>()?;
+        self.reference = Some(RefSeqs { fasta: Fasta::open(fasta)?, names });
+        Ok(self)
+    }
+
+    //score of one SAM/BAM/CRAM record
+    pub(crate) fn record(&self, rec: &Record) -> Result<f64, String> {
+        self.score(&Aln::Rec(rec))
+    }
+
+    //score of one PAF/GAF line
+    pub(crate) fn line(&self, line: &str) -> Result<f64, String> {
+        self.score(&Aln::Line(line.split('\t').collect()))
+    }
+
+    fn score(&self, aln: &Aln) -> Result<f64, String> {
+        let missing = |tag: &str| format!("Read '{}' is missing the '{}' tag", aln.qname(), tag);
+        let diffs = match &self.source {
+            ScoreSource::Tag(tag) => return aln.number(tag)?.ok_or_else(|| missing(tag)),
+            ScoreSource::Cs => {
+                let cs = aln.text("cs").ok_or_else(|| missing("cs"))?;
+                cs_diffs(cs).ok_or_else(|| format!("Read '{}' has a malformed cs tag '{}'", aln.qname(), cs))?
+            }
+            ScoreSource::Cigar => {
+                let sums = aln.cigar_sums("cigar")?;
+                if sums.plain_m {
+                    return Err(format!("Read '{}' has M operations in its CIGAR, which do not tell matches from mismatches; \
+                        --score cigar needs =/X operations (e.g. minimap2 --eqx)", aln.qname()));
+                }
+                Diffs { matches: sums.equal, mismatches: sums.diff, gap_opens: sums.gap_opens, gap_len: sums.gap_len }
+            }
+            ScoreSource::Nm => {
+                let nm = aln.number("NM")?.ok_or_else(|| missing("NM"))?;
+                let sums = aln.cigar_sums("nm")?;
+                //NM counts mismatches and every inserted and deleted base
+                let mismatches = (nm.max(0.0) as u64).saturating_sub(sums.gap_len).min(sums.aligned);
+                Diffs { matches: sums.aligned - mismatches, mismatches, gap_opens: sums.gap_opens, gap_len: sums.gap_len }
+            }
+            ScoreSource::De => {
+                let de = aln.number("de")?.ok_or_else(|| missing("de"))?;
+                let sums = aln.cigar_sums("de")?;
+                //gap-compressed divergence: (mismatches + gap opens) / (aligned bases + gap opens)
+                let diffs = (de.clamp(0.0, 1.0) * (sums.aligned + sums.gap_opens) as f64).round() as u64;
+                let mismatches = diffs.saturating_sub(sums.gap_opens).min(sums.aligned);
+                Diffs { matches: sums.aligned - mismatches, mismatches, gap_opens: sums.gap_opens, gap_len: sums.gap_len }
+            }
+            ScoreSource::Ref => match aln {
+                Aln::Rec(rec) => self.ref_diffs(rec)?,
+                Aln::Line(_) => return Err(format!("Read '{}' is a PAF/GAF line, which --score ref cannot rescore", aln.qname())),
+            },
+        };
+        Ok(self.scheme.score(&diffs))
+    }
+
+    //differences of a record to the reference, counted base by base along its CIGAR
+    //M, = and X are all compared, so the aligner's own =/X, NM, MD and AS do not matter; N bases count as neither match nor mismatch
+    fn ref_diffs(&self, rec: &Record) -> Result<Diffs, String> {
+        let qname = || String::from_utf8_lossy(rec.qname()).into_owned();
+        let reference = self.reference.as_ref().ok_or("--score ref needs the reference FASTA of every input")?;
+        if rec.seq_len() == 0 {
+            return Err(format!("Read '{}' has no sequence in a non-secondary record, which --score ref needs", qname()));
+        }
+        let name = reference.names.get(rec.tid() as usize)
+            .ok_or_else(|| format!("Read '{}' is aligned to no contig of the header", qname()))?;
+        let start = rec.pos();
+        let end = rec.cigar().end_pos();
+        let ref_seq = reference.fasta.fetch(name, start, end)?;
+        let seq = rec.seq().as_bytes();
+
+        let mut d = Diffs::default();
+        let (mut q, mut r) = (0usize, 0usize);
+        for c in rec.cigar().iter() {
+            let len = c.len() as usize;
+            match c.char() {
+                'M' | '=' | 'X' => {
+                    let (Some(read), Some(refs)) = (seq.get(q..q + len), ref_seq.get(r..r + len)) else {
+                        return Err(format!("Read '{}' has a CIGAR longer than its sequence or the reference", qname()));
+                    };
+                    for (a, b) in read.iter().zip(refs.iter()) {
+                        if *a == b'N' || *b == b'N' {
+                            continue;
+                        } else if a == b {
+                            d.matches += 1;
+                        } else {
+                            d.mismatches += 1;
+                        }
+                    }
+                    q += len;
+                    r += len;
+                }
+                'I' => { d.gap_opens += 1; d.gap_len += len as u64; q += len; }
+                'D' => { d.gap_opens += 1; d.gap_len += len as u64; r += len; }
+                'N' => r += len,
+                'S' => q += len,
+                _ => {}
+            }
+        }
+        Ok(d)
+    }
+}
+
+impl Aln<'_> {
+    fn qname(&self) -> String {
+        match self {
+            Aln::Rec(rec) => String::from_utf8_lossy(rec.qname()).into_owned(),
+            Aln::Line(fields) => fields[0].to_string(),
+        }
+    }
+
+    //value of an integer or float tag, None if it is missing
+    fn number(&self, tag: &str) -> Result<Option<f64>, String> {
+        match self {
+            //is not the same integer type in every sam file so check every possile type to be robust
+            Aln::Rec(rec) => match rec.aux(tag.as_bytes()) {
+                Ok(Aux::I8(v)) => Ok(Some(v as f64)),
+                Ok(Aux::I16(v)) => Ok(Some(v as f64)),
+                Ok(Aux::I32(v)) => Ok(Some(v as f64)),
+                Ok(Aux::U8(v)) => Ok(Some(v as f64)),
+                Ok(Aux::U16(v)) => Ok(Some(v as f64)),
+                Ok(Aux::U32(v)) => Ok(Some(v as f64)),
+                Ok(Aux::Float(v)) => Ok(Some(v as f64)),
+                Ok(Aux::Double(v)) => Ok(Some(v)),
+                Ok(_) => Err(format!("Read '{}' has a non-numeric '{}' tag", self.qname(), tag)),
+                Err(_) => Ok(None),
+            },
+            Aln::Line(fields) => {
+                for field in fields.iter().skip(12) {
+                    let Some(rest) = field.strip_prefix(tag).and_then(|r| r.strip_prefix(':')) else { continue };
+                    let value = match rest.split_once(':') {
+                        Some(("i", v)) | Some(("f", v)) => v,
+                        _ => return Err(format!("Read '{}' has a non-numeric '{}' tag", self.qname(), tag)),
+                    };
+                    return value.parse().map(Some)
+                        .map_err(|e| format!("Invalid {} value '{}' for read '{}': {}", tag, value, self.qname(), e));
+                }
+                Ok(None)
+            }
+        }
+    }
+
+    //value of a string tag, None if it is missing
+    fn text(&self, tag: &str) -> Option<&str> {
+        match self {
+            Aln::Rec(rec) => match rec.aux(tag.as_bytes()) {
+                Ok(Aux::String(s)) => Some(s),
+                _ => None,
+            },
+            Aln::Line(fields) => {
+                let prefix = format!("{}:Z:", tag);
+                fields.iter().skip(12).find_map(|f| f.strip_prefix(prefix.as_str()))
+            }
+        }
+    }
+
+    //CIGAR of the alignment, from the cg:Z: tag of PAF/GAF lines, which source names the --score that needs it
+    fn cigar_sums(&self, source: &str) -> Result<CigarSums, String> {
+        let ops: Vec<(char, u32)> = match self {
+            Aln::Rec(rec) => rec.cigar().iter().map(|c| (c.char(), c.len())).collect(),
+            Aln::Line(_) => {
+                let cg = self.text("cg").ok_or_else(|| format!("Read '{}' is missing the 'cg' tag, which --score {} needs for PAF/GAF alignments", self.qname(), source))?;
+                parse_cigar(cg).ok_or_else(|| format!("Read '{}' has a malformed cg tag '{}'", self.qname(), cg))?
+            }
+        };
+        if ops.is_empty() {
+            return Err(format!("Read '{}' has no CIGAR, which --score {} needs", self.qname(), source));
+        }
+        let mut sums = CigarSums::default();
+        for (op, len) in ops {
+            let len = len as u64;
+            match op {
+                'M' => { sums.aligned += len; sums.plain_m = true; }
+                '=' => { sums.aligned += len; sums.equal += len; }
+                'X' => { sums.aligned += len; sums.diff += len; }
+                'I' | 'D' => { sums.gap_opens += 1; sums.gap_len += len; }
+                _ => {}
+            }
+        }
+        Ok(sums)
+    }
+}
+
+//operations of a CIGAR string, e.g. 10=1X2I5=
+fn parse_cigar(cg: &str) -> Option<Vec<(char, u32)>> {
+    let mut ops = Vec::new();
+    let mut len: Option<u32> = None;
+    for c in cg.chars() {
+        if let Some(d) = c.to_digit(10) {
+            len = Some(len.unwrap_or(0).checked_mul(10)?.checked_add(d)?);
+        } else if "MIDNSHP=X".contains(c) {
+            ops.push((c, len.take()?));
+        } else {
+            return None;
+        }
+    }
+    if len.is_some() { return None; }
+    Some(ops)
+}
+
+//differences of a cs tag, in short (:10*ag+ct-a) or long (=ACGT*ag) form; introns (~) are skipped
+fn cs_diffs(cs: &str) -> Option<Diffs> {
+    let b = cs.as_bytes();
+    let mut d = Diffs::default();
+    let mut i = 0;
+    while i < b.len() {
+        let op = b[i];
+        i += 1;
+        let start = i;
+        match op {
+            b':' => {
+                while i < b.len() && b[i].is_ascii_digit() { i += 1; }
+                d.matches += cs[start..i].parse::<u64>().ok()?;
+            }
+            b'=' => {
+                while i < b.len() && b[i].is_ascii_alphabetic() { i += 1; }
+                d.matches += (i - start) as u64;
+            }
+            b'*' => {
+                i += 2;
+                if i > b.len() || !b[start..i].iter().all(|c| c.is_ascii_alphabetic()) { return None; }
+                d.mismatches += 1;
+            }
+            b'+' | b'-' => {
+                while i < b.len() && b[i].is_ascii_alphabetic() { i += 1; }
+                d.gap_opens += 1;
+                d.gap_len += (i - start) as u64;
+            }
+            b'~' => {
+                while i < b.len() && b[i].is_ascii_alphanumeric() { i += 1; }
+            }
+            _ => return None,
+        }
+        if i == start { return None; }
+    }
+    Some(d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(d: Diffs) -> (u64, u64, u64, u64) {
+        (d.matches, d.mismatches, d.gap_opens, d.gap_len)
+    }
+
+    #[test]
+    fn cs_short_and_long_form_agree() {
+        let short = cs_diffs(":10*ag:5+ct:3-a:2").unwrap();
+        let long = cs_diffs("=ACGTACGTAC*ag=ACGTA+ct=ACG-a=AC").unwrap();
+        assert_eq!(counts(short), (20, 1, 2, 3));
+        assert_eq!(counts(long), (20, 1, 2, 3));
+        //consecutive mismatches, and a spliced alignment whose intron is no gap
+        assert_eq!(counts(cs_diffs(":4*ag*ct:4").unwrap()), (8, 2, 0, 0));
+        assert_eq!(counts(cs_diffs(":4~gt120ag:6").unwrap()), (10, 0, 0, 0));
+    }
+
+    #[test]
+    fn cs_rejects_truncated_ops() {
+        assert!(cs_diffs(":10*a").is_none());
+        assert!(cs_diffs(":10:").is_none());
+        assert!(cs_diffs(":10-").is_none());
+        assert!(cs_diffs(":10*a+").is_none());
+        assert!(cs_diffs("10").is_none());
+    }
+
+    #[test]
+    fn cigar_ops_and_malformed_strings() {
+        assert_eq!(parse_cigar("10=1X2I5=3D"), Some(vec![('=', 10), ('X', 1), ('I', 2), ('=', 5), ('D', 3)]));
+        assert_eq!(parse_cigar(""), Some(vec![]));
+        assert_eq!(parse_cigar("="), None);
+        assert_eq!(parse_cigar("10M5"), None);
+        assert_eq!(parse_cigar("5Q"), None);
+        assert_eq!(parse_cigar("99999999999M"), None);
+    }
+
+    #[test]
+    fn cigar_sums_count_eqx_apart_from_m() {
+        let line = "r1\t30\t0\t30\t+\tctg\t100\t0\t29\t25\t30\t60\tcg:Z:10=1X2I5=3D2X5S";
+        let fields: Vec<&str> = line.split('\t').collect();
+        let sums = Aln::Line(fields).cigar_sums("cigar").unwrap();
+        assert_eq!((sums.aligned, sums.equal, sums.diff, sums.plain_m), (18, 15, 3, false));
+        assert_eq!((sums.gap_opens, sums.gap_len), (2, 5));
+        let line = "r1\t30\t0\t30\t+\tctg\t100\t0\t30\t25\t30\t60\tcg:Z:20M1X9=";
+        let sums = Aln::Line(line.split('\t').collect()).cigar_sums("cigar").unwrap();
+        assert_eq!((sums.aligned, sums.equal, sums.diff, sums.plain_m), (30, 9, 1, true));
+    }
+}
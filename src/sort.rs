@@ -0,0 +1,401 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+use rust_htslib::{
+    bam::{self, Read, Record, Writer},
+    errors::Error as BamError,
+};
+
+use crate::cli::NameOrder;
+
+//comparison used to order records, e.g. by read name
+pub type RecordCmp = fn(&Record, &Record) -> Ordering;
+
+//counter to give every spill file of this process a unique name
+static SPILL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+//compare read names the way `samtools sort -n` does by default ("natural" order):
+//runs of digits are compared by numeric value, everything else byte by byte
+//port of strnum_cmp from samtools bam_sort.c
+pub fn natural_cmp(a: &[u8], b: &[u8]) -> Ordering {
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() && j < b.len() {
+        if !a[i].is_ascii_digit() || !b[j].is_ascii_digit() {
+            if a[i] != b[j] {
+                return a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        } else {
+            //skip leading zeros
+            while i < a.len() && a[i] == b'0' { i += 1; }
+            while j < b.len() && b[j] == b'0' { j += 1; }
+            //skip matching digits
+            while i < a.len() && j < b.len() && a[i].is_ascii_digit() && a[i] == b[j] {
+                i += 1;
+                j += 1;
+            }
+            //now mismatching, remember the first differing digit and see which number ends sooner
+            let diff = a.get(i).copied().unwrap_or(0).cmp(&b.get(j).copied().unwrap_or(0));
+            while i < a.len() && j < b.len() && a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+                i += 1;
+                j += 1;
+            }
+            let a_digit = i < a.len() && a[i].is_ascii_digit();
+            let b_digit = j < b.len() && b[j].is_ascii_digit();
+            if a_digit {
+                return Ordering::Greater; //a still going, so larger number
+            } else if b_digit {
+                return Ordering::Less; //b still going, so larger number
+            } else if diff != Ordering::Equal {
+                return diff; //same length, so earlier diff decides
+            }
+        }
+    }
+    (a.len() - i).cmp(&(b.len() - j))
+}
+
+//order records by read name in natural order
+pub fn natural_name_cmp(a: &Record, b: &Record) -> Ordering {
+    natural_cmp(a.qname(), b.qname())
+}
+
+//order records by read name byte by byte (`samtools sort -N`, `LC_ALL=C sort`)
+pub fn lexicographic_name_cmp(a: &Record, b: &Record) -> Ordering {
+    a.qname().cmp(b.qname())
+}
+
+//order records by position like `samtools sort`: target id with unmapped reads last, position, then strand
+pub fn coordinate_cmp(a: &Record, b: &Record) -> Ordering {
+    (a.tid() as u32).cmp(&(b.tid() as u32))
+        .then(a.pos().cmp(&b.pos()))
+        .then(a.is_reverse().cmp(&b.is_reverse()))
+}
+
+impl NameOrder {
+    //compare two read names in this order
+    pub fn cmp_names(self, a: &[u8], b: &[u8]) -> Ordering {
+        match self {
+            NameOrder::Natural => natural_cmp(a, b),
+            NameOrder::Lexicographic => a.cmp(b),
+        }
+    }
+
+    //record comparison for sorting input into this order
+    pub fn record_cmp(self) -> RecordCmp {
+        match self {
+            NameOrder::Natural => natural_name_cmp,
+            NameOrder::Lexicographic => lexicographic_name_cmp,
+        }
+    }
+}
+
+//record waiting in the merge heap, tagged with the spill file it came from
+struct HeapEntry {
+    rec: Record,
+    src: usize,
+    cmp: RecordCmp,
+}
+
+impl Ord for HeapEntry {
+    //BinaryHeap pops the greatest entry, so reverse the record order
+    //equal records come out in spill file order, which keeps the sort stable
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.cmp)(&other.rec, &self.rec).then(other.src.cmp(&self.src))
+    }
+}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+//iterator over sorted records, either straight from memory or merged from spill files
+pub struct SortedRecords {
+    //records kept in memory when the input fit under the memory limit (reversed, so pop() yields in order)
+    in_memory: Vec<Record>,
+    spills: Vec<bam::Reader>,
+    heap: BinaryHeap<HeapEntry>,
+    paths: Vec<PathBuf>,
+}
+
+impl Iterator for SortedRecords {
+    type Item = Result<Record, BamError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.spills.is_empty() {
+            return self.in_memory.pop().map(Ok);
+        }
+        //take the smallest record and refill the heap from the same spill file
+        let entry = self.heap.pop()?;
+        let mut next = Record::new();
+        match self.spills[entry.src].read(&mut next) {
+            Some(Ok(())) => self.heap.push(HeapEntry { rec: next, src: entry.src, cmp: entry.cmp }),
+            Some(Err(e)) => return Some(Err(e)),
+            None => {} //spill file exhausted
+        }
+        Some(Ok(entry.rec))
+    }
+}
+
+impl Drop for SortedRecords {
+    //temporary spill files are only needed while the records are being read
+    fn drop(&mut self) {
+        for path in self.paths.iter() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+//sort a record stream with at most mem_limit bytes of records held in memory
+//records beyond the limit are sorted in chunks and spilled to fast-compressed BAM files in tmp_dir,
+//which are then merged on the fly; nothing touches the disk if the whole input fits in memory
+pub fn sort_records<I>(records: I, header: &bam::HeaderView, cmp: RecordCmp, mem_limit: usize, tmp_dir: &Path) -> Result<SortedRecords, Box<dyn std::error::Error>>
+where
+    I: Iterator<Item = Result<Record, BamError>>,
+{
+    let mut sorter = RecordSorter::new(header, cmp, mem_limit, tmp_dir);
+    for rec in records {
+        sorter.push(rec?)?;
+    }
+    sorter.finish()
+}
+
+//the memory-bounded sort of sort_records, for records that arrive one at a time (e.g. sorted output)
+pub struct RecordSorter {
+    header: bam::HeaderView,
+    cmp: RecordCmp,
+    mem_limit: usize,
+    tmp_dir: PathBuf,
+    buffer: Vec<Record>,
+    buffer_bytes: usize,
+    //spill files are owned by the result from the start, so they get removed on error as well
+    sorted: SortedRecords,
+}
+
+impl RecordSorter {
+    pub fn new(header: &bam::HeaderView, cmp: RecordCmp, mem_limit: usize, tmp_dir: &Path) -> Self {
+        RecordSorter {
+            header: header.clone(),
+            cmp,
+            mem_limit,
+            tmp_dir: tmp_dir.to_path_buf(),
+            buffer: Vec::new(),
+            buffer_bytes: 0,
+            sorted: SortedRecords { in_memory: Vec::new(), spills: Vec::new(), heap: BinaryHeap::new(), paths: Vec::new() },
+        }
+    }
+
+    pub fn push(&mut self, rec: Record) -> Result<(), Box<dyn std::error::Error>> {
+        self.buffer_bytes += std::mem::size_of::<Record>() + rec.inner().l_data as usize;
+        self.buffer.push(rec);
+        if self.buffer_bytes >= self.mem_limit {
+            spill_chunk(&mut self.buffer, &self.header, self.cmp, &self.tmp_dir, &mut self.sorted.paths)?;
+            self.buffer_bytes = 0;
+        }
+        Ok(())
+    }
+
+    //sort what is left and return the records in order
+    pub fn finish(self) -> Result<SortedRecords, Box<dyn std::error::Error>> {
+        let RecordSorter { header, cmp, tmp_dir, mut buffer, mut sorted, .. } = self;
+
+        //everything fit in memory, no need to touch the disk
+        if sorted.paths.is_empty() {
+            //stable sort keeps the input order of records that compare equal
+            buffer.sort_by(cmp);
+            buffer.reverse();
+            sorted.in_memory = buffer;
+            return Ok(sorted);
+        }
+        if !buffer.is_empty() {
+            spill_chunk(&mut buffer, &header, cmp, &tmp_dir, &mut sorted.paths)?;
+        }
+
+        //open every spill file and seed the merge heap with its first record
+        for src in 0..sorted.paths.len() {
+            let path = &sorted.paths[src];
+            let mut reader = bam::Reader::from_path(path)
+                .map_err(|e| format!("Failed to open temporary file '{}': {}", path.display(), e))?;
+            let mut rec = Record::new();
+            if let Some(res) = reader.read(&mut rec) {
+                res?;
+                sorted.heap.push(HeapEntry { rec, src, cmp });
+            }
+            sorted.spills.push(reader);
+        }
+        Ok(sorted)
+    }
+}
+
+//sort the buffered records and write them to a new temporary BAM file, emptying the buffer
+//the file path is recorded in paths before writing, so partial files are cleaned up too
+fn spill_chunk(buffer: &mut Vec<Record>, header: &bam::HeaderView, cmp: RecordCmp, tmp_dir: &Path, paths: &mut Vec<PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+    buffer.sort_by(cmp);
+    let path = tmp_dir.join(format!("diplinator.{}.{}.tmp.bam", std::process::id(),
+        SPILL_COUNT.fetch_add(1, AtomicOrdering::Relaxed)));
+    paths.push(path.clone());
+    let mut writer = Writer::from_path(&path, &bam::Header::from_template(header), bam::Format::Bam)
+        .map_err(|e| format!("Failed to create temporary file '{}': {}", path.display(), e))?;
+    //spill files are read back once, so favour speed over size
+    writer.set_compression_level(bam::CompressionLevel::Fastest)?;
+    for rec in buffer.drain(..) {
+        writer.write(&rec)?;
+    }
+    Ok(())
+}
+
+//order PAF/GAF lines by target name (unmapped '*' last) and target start
+pub fn target_cmp(a: &str, b: &str) -> Ordering {
+    target_key(a).cmp(&target_key(b))
+}
+
+fn target_key(line: &str) -> (bool, &str, u64) {
+    let mut fields = line.split('\t').skip(5);
+    let target = fields.next().unwrap_or("*");
+    let start = fields.nth(1).and_then(|s| s.parse::<u64>().ok()).unwrap_or(0);
+    (target == "*", target, start)
+}
+
+//line waiting in the merge heap of LineSorter, tagged with the spill file it came from
+struct LineEntry {
+    line: String,
+    src: usize,
+}
+
+impl Ord for LineEntry {
+    //reversed for the max-heap, equal lines come out in spill file order
+    fn cmp(&self, other: &Self) -> Ordering {
+        target_cmp(&other.line, &self.line).then(other.src.cmp(&self.src))
+    }
+}
+
+impl PartialOrd for LineEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for LineEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for LineEntry {}
+
+//memory-bounded sort of PAF/GAF output lines by target, like RecordSorter
+//chunks beyond the memory limit are spilled to plain text files in tmp_dir
+pub struct LineSorter {
+    mem_limit: usize,
+    tmp_dir: PathBuf,
+    buffer: Vec<String>,
+    buffer_bytes: usize,
+    paths: Vec<PathBuf>,
+}
+
+impl LineSorter {
+    pub fn new(mem_limit: usize, tmp_dir: &Path) -> Self {
+        LineSorter { mem_limit, tmp_dir: tmp_dir.to_path_buf(), buffer: Vec::new(), buffer_bytes: 0, paths: Vec::new() }
+    }
+
+    pub fn push(&mut self, line: String) -> io::Result<()> {
+        self.buffer_bytes += std::mem::size_of::<String>() + line.len();
+        self.buffer.push(line);
+        if self.buffer_bytes >= self.mem_limit {
+            self.spill()?;
+        }
+        Ok(())
+    }
+
+    //sort the buffered lines into a new temporary file, emptying the buffer
+    fn spill(&mut self) -> io::Result<()> {
+        self.buffer.sort_by(|a, b| target_cmp(a, b));
+        let path = self.tmp_dir.join(format!("diplinator.{}.{}.tmp.paf", std::process::id(),
+            SPILL_COUNT.fetch_add(1, AtomicOrdering::Relaxed)));
+        self.paths.push(path.clone());
+        let mut writer = BufWriter::new(File::create(&path)?);
+        for line in self.buffer.drain(..) {
+            writeln!(writer, "{}", line)?;
+        }
+        self.buffer_bytes = 0;
+        writer.flush()
+    }
+
+    //write all lines to out in order
+    pub fn finish(mut self, out: &mut dyn Write) -> io::Result<()> {
+        if self.paths.is_empty() {
+            self.buffer.sort_by(|a, b| target_cmp(a, b));
+            for line in self.buffer.iter() {
+                writeln!(out, "{}", line)?;
+            }
+            return Ok(());
+        }
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        let mut spills = Vec::with_capacity(self.paths.len());
+        let mut heap = BinaryHeap::new();
+        for (src, path) in self.paths.iter().enumerate() {
+            let mut lines = BufReader::new(File::open(path)?).lines();
+            if let Some(line) = lines.next() {
+                heap.push(LineEntry { line: line?, src });
+            }
+            spills.push(lines);
+        }
+        while let Some(entry) = heap.pop() {
+            writeln!(out, "{}", entry.line)?;
+            if let Some(line) = spills[entry.src].next() {
+                heap.push(LineEntry { line: line?, src: entry.src });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for LineSorter {
+    fn drop(&mut self) {
+        for path in self.paths.iter() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn natural_cmp_digit_runs() {
+        assert_eq!(natural_cmp(b"r2", b"r10"), Ordering::Less);
+        assert_eq!(natural_cmp(b"r9", b"r10"), Ordering::Less);
+        assert_eq!(natural_cmp(b"r10", b"r9x"), Ordering::Greater);
+        assert_eq!(natural_cmp(b"r12a", b"r12b"), Ordering::Less);
+        assert_eq!(natural_cmp(b"r1_20", b"r1_3"), Ordering::Greater);
+        assert_eq!(natural_cmp(b"r1", b"r1a"), Ordering::Less);
+        assert_eq!(natural_cmp(b"r1/1", b"r1/1"), Ordering::Equal);
+    }
+
+    #[test]
+    fn natural_cmp_leading_zeros() {
+        //like samtools, zero-padded numbers compare by value
+        assert_eq!(natural_cmp(b"r007", b"r7"), Ordering::Equal);
+        assert_eq!(natural_cmp(b"r0", b"r00"), Ordering::Equal);
+        assert_eq!(natural_cmp(b"r01b", b"r1a"), Ordering::Greater);
+        assert_eq!(natural_cmp(b"r010", b"r9"), Ordering::Greater);
+        assert_eq!(natural_cmp(b"x0", b"x"), Ordering::Greater);
+    }
+}
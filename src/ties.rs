@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+use crate::cli::{Cli, TieCriterion};
+
+//what ties in score are broken by (--tie-break), from the alignments of a read to one tied input
+#[derive(Debug, Clone, Default)]
+pub struct TieStats {
+    //non-secondary alignments
+    pub splits: u32,
+    //lowest MAPQ of the primary record(s), None if unavailable (255)
+    pub mapq: Option<u8>,
+    //NM summed over the non-secondary alignments, None if one lacks it
+    pub nm: Option<i64>,
+    //read bases aligned by the non-secondary alignments
+    pub span: u64,
+    //contig and 0-based start of the primary alignment, which windows of --tie-balance count the read in
+    pub locus: Option<(Vec<u8>, i64)>,
+}
+
+//input, contig and window index of a read with --tie-balance
+type WindowKey = (usize, Vec<u8>, i64);
+
+//resolves reads with equal scores in several inputs into the inputs they are written to
+pub struct TieBreaker {
+    criteria: Vec<TieCriterion>,
+    both: bool,
+    prefer: Option<usize>,
+    seed: u64,
+    //window size of --tie-balance
+    window: Option<u64>,
+    //reads written to every input per window (input, contig, window index) with --tie-balance
+    depth: HashMap<WindowKey, u64>,
+}
+
+impl TieBreaker {
+    pub fn new(args: &Cli, labels: &[String]) -> Result<Self, String> {
+        Ok(TieBreaker { criteria: args.tie_break.clone(), both: args.both, prefer: args.tie_prefer_index(labels)?, seed: args.tie_seed,
+            window: args.tie_balance, depth: HashMap::new() })
+    }
+
+    //with --tie-balance, count a read won by one input in the depth of its window
+    pub fn won(&mut self, winner: usize, stats: impl Fn(usize) -> TieStats) {
+        if self.window.is_none() { return; }
+        if let Some(key) = self.window_key(winner, stats(winner)) {
+            *self.depth.entry(key).or_insert(0) += 1;
+        }
+    }
+
+    fn window_key(&self, input: usize, stats: TieStats) -> Option<WindowKey> {
+        let window = self.window?;
+        let (contig, pos) = stats.locus?;
+        Some((input, contig, pos / window as i64))
+    }
+
+    //the criteria of --tie-break narrow the tied inputs in order, the read then goes to all that remain with --both,
+    //else to the --tie-prefer input if it remains, else to one picked by read name hash
+    //with --tie-balance, it goes to the one with the fewest reads in its window instead, the hash picking among equal ones
+    pub fn resolve(&mut self, tied: &[usize], qname: &[u8], stats: impl Fn(usize) -> TieStats) -> Vec<usize> {
+        if self.window.is_some() {
+            let remaining = self.cascade(tied.iter().map(|&i| (i, stats(i))).collect());
+            return vec![self.balance(remaining, qname)];
+        }
+        let tied: Vec<usize> = if self.criteria.is_empty() {
+            tied.to_vec()
+        } else {
+            self.cascade(tied.iter().map(|&i| (i, stats(i))).collect()).into_iter().map(|(i, _)| i).collect()
+        };
+        if self.both || tied.len() == 1 {
+            return tied;
+        }
+        match self.prefer {
+            Some(p) if tied.contains(&p) => vec![p],
+            _ => vec![crate::choose_random(qname, &tied, self.seed)],
+        }
+    }
+
+    fn cascade(&self, mut tied: Vec<(usize, TieStats)>) -> Vec<(usize, TieStats)> {
+        for &criterion in self.criteria.iter() {
+            if tied.len() == 1 { break; }
+            tied = narrow(tied, criterion);
+        }
+        tied
+    }
+
+    //the tied input whose window holds the fewest reads so far, which the read is then counted in;
+    //an input without a locus counts as an empty window
+    fn balance(&mut self, tied: Vec<(usize, TieStats)>, qname: &[u8]) -> usize {
+        let keyed: Vec<(usize, Option<WindowKey>)> = tied.into_iter().map(|(i, s)| (i, self.window_key(i, s))).collect();
+        let count = |key: &Option<WindowKey>| key.as_ref().and_then(|k| self.depth.get(k)).copied().unwrap_or(0);
+        let fewest = keyed.iter().map(|(_, key)| count(key)).min().unwrap();
+        let lowest: Vec<usize> = keyed.iter().filter(|(_, key)| count(key) == fewest).map(|&(i, _)| i).collect();
+        let chosen = if lowest.len() == 1 { lowest[0] } else { crate::choose_random(qname, &lowest, self.seed) };
+        if let Some((_, Some(key))) = keyed.into_iter().find(|(i, _)| *i == chosen) {
+            *self.depth.entry(key).or_insert(0) += 1;
+        }
+        chosen
+    }
+}
+
+//the tied inputs that are best by one criterion; all of them if it is unknown for one
+fn narrow(tied: Vec<(usize, TieStats)>, criterion: TieCriterion) -> Vec<(usize, TieStats)> {
+    //higher is better
+    let value = |s: &TieStats| -> Option<i64> {
+        match criterion {
+            TieCriterion::Splits => Some(-(s.splits as i64)),
+            TieCriterion::Mapq => s.mapq.map(|q| q as i64),
+            TieCriterion::Nm => s.nm.map(|nm| -nm),
+            TieCriterion::Span => Some(s.span as i64),
+        }
+    };
+    let Some(values) = tied.iter().map(|(_, s)| value(s)).collect::<Option<Vec<i64>>>() else { return tied };
+    let best = *values.iter().max().unwrap();
+    tied.into_iter().zip(values).filter(|&(_, v)| v == best).map(|(t, _)| t).collect()
+}